@@ -1,14 +1,18 @@
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::Path;
-use toml;
+use std::process::Command;
+use toml::value::{Table, Value};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub scraper: ScraperConfig,
+    pub storage: Option<StorageConfig>,
 }
 
 impl fmt::Display for Config {
@@ -19,12 +23,21 @@ impl fmt::Display for Config {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct ServerConfig {
-    // Host address to listen on
+    // Host address to listen on, defaults to all interfaces
+    #[serde(default = "default_host")]
     pub host: String,
     // Port to listen on
     pub port: u16,
+    // Bearer token required for topic management, which is disabled when absent
+    pub admin_token: Option<Secret>,
+    // Seconds to wait for in-flight requests & tweets to be processed on shutdown
+    pub shutdown_timeout: Option<u64>,
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_owned()
 }
 
 impl fmt::Display for ScraperConfig {
@@ -37,92 +50,987 @@ impl fmt::Display for ScraperConfig {
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ScraperConfig {
-    pub consumer_key: String,
-    pub consumer_secret: String,
-    pub access_key: String,
-    pub access_secret: String,
+    // Twitter API credentials, which can be omitted when replaying recorded tweets
+    #[serde(default)]
+    pub consumer_key: Secret,
+    #[serde(default)]
+    pub consumer_secret: Secret,
+    #[serde(default)]
+    pub access_key: Secret,
+    #[serde(default)]
+    pub access_secret: Secret,
     pub topics: Vec<String>,
     pub batch_size: Option<usize>,
+    pub shed_threshold: Option<u8>,
+    pub retweets: Option<RetweetPolicy>,
+    pub record: Option<RecordConfig>,
+    pub retention: Option<RetentionConfig>,
+    pub reconnect: Option<ReconnectConfig>,
+    pub queue: Option<QueueConfig>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub overrides: HashMap<String, TopicConfig>,
 }
 
-pub fn load_config(config_path: &str) -> Result<Config, String> {
-    let mut file_str = String::new();
-    let file_path = Path::new(config_path);
-    let mut open_file = File::open(file_path).or_else(|e| {
-        Err(format!(
-            "Could not open file: {file}. Reason: {error}",
-            file = config_path,
-            error = e
-        ))
-    })?;
-    open_file.read_to_string(&mut file_str).or_else(|e| {
+impl ScraperConfig {
+    /// Retention policy of a topic, taking topic specific overrides into account
+    pub fn retention_for(&self, topic: &str) -> RetentionConfig {
+        self.overrides
+            .get(topic)
+            .and_then(|overrides| overrides.retention.clone())
+            .or_else(|| self.retention.clone())
+            .unwrap_or_default()
+    }
+
+    /// Retweet policy of a topic, taking topic specific overrides into account
+    pub fn retweets_for(&self, topic: &str) -> RetweetPolicy {
+        self.overrides
+            .get(topic)
+            .and_then(|overrides| overrides.retweets)
+            .or(self.retweets)
+            .unwrap_or_default()
+    }
+}
+
+/// Treatment of retweets in the sentiment time series
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RetweetPolicy {
+    // Every retweet is scored as an independent sample
+    #[default]
+    Count,
+    // Retweets are not scored
+    Ignore,
+    // Each original status is scored once, counting as several samples depending on its retweet count
+    Weight,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RetentionConfig {
+    // Maximum number of samples kept per topic
+    pub max_samples: Option<usize>,
+    // Maximum age of samples in seconds
+    pub max_age: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TopicConfig {
+    // Topic specific retweet policy
+    pub retweets: Option<RetweetPolicy>,
+    // Topic specific retention policy
+    pub retention: Option<RetentionConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RecordConfig {
+    // Directory the raw stream archives are written to
+    pub directory: String,
+    // Rotate archive files once this many bytes have been written
+    pub max_file_size: Option<u64>,
+    // Rotate archive files once they have been open for this many seconds
+    pub max_file_age: Option<u64>,
+    // Gzip compress the archive files
+    pub compress: Option<bool>,
+}
+
+/// Bounded queue of received tweets per topic, consumed by a pool of scoring workers
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct QueueConfig {
+    // Maximum number of tweets queued per topic
+    pub capacity: Option<usize>,
+    // Treatment of tweets arriving at a full queue
+    pub overflow: Option<OverflowPolicy>,
+    // Number of workers scoring the tweets of all topics
+    pub workers: Option<usize>,
+}
+
+/// Treatment of tweets arriving at a full queue
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // Wait for room in the queue, slowing down the stream for all topics
+    #[default]
+    Block,
+    // Discard the oldest queued tweet
+    DropOldest,
+    // Keep a uniform sample of the tweets arriving at the full queue, replacing random queued tweets
+    Sample,
+}
+
+// Default backoff of the filter connection, following the guidelines of the streaming API
+pub const DEFAULT_NETWORK_STEP_MS: u64 = 250;
+pub const DEFAULT_NETWORK_MAX_MS: u64 = 16_000;
+pub const DEFAULT_HTTP_INITIAL_MS: u64 = 5_000;
+pub const DEFAULT_HTTP_MAX_MS: u64 = 320_000;
+pub const DEFAULT_RATE_LIMIT_INITIAL_MS: u64 = 60_000;
+pub const DEFAULT_RATE_LIMIT_MAX_MS: u64 = 960_000;
+pub const DEFAULT_JITTER: f64 = 0.25;
+
+/// Backoff between reconnection attempts of the filter connection
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct ReconnectConfig {
+    // Linear backoff increment in milliseconds after network errors
+    pub network_step_ms: Option<u64>,
+    // Maximum backoff in milliseconds after network errors
+    pub network_max_ms: Option<u64>,
+    // Initial backoff in milliseconds after HTTP errors, doubled on every consecutive error
+    pub http_initial_ms: Option<u64>,
+    // Maximum backoff in milliseconds after HTTP errors
+    pub http_max_ms: Option<u64>,
+    // Initial backoff in milliseconds after being rate limited, doubled on every consecutive error
+    pub rate_limit_initial_ms: Option<u64>,
+    // Maximum backoff in milliseconds after being rate limited
+    pub rate_limit_max_ms: Option<u64>,
+    // Fraction by which every backoff is randomly shortened, between 0 and 1
+    pub jitter: Option<f64>,
+    // Reconnect when no data was received for this many milliseconds
+    pub idle_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    // Samples are only kept in memory
+    Memory,
+    // Samples are persisted in append-only segment files
+    Segment { directory: String },
+}
+
+/// Placeholder for secrets in printed configurations
+const REDACTED: &str = "<redacted>";
+/// Prefix of environment variables overriding individual config keys
+const ENV_PREFIX: &str = "SIBYL_";
+/// Separator between the nested keys of an environment variable, e.g. `SIBYL_SERVER__PORT`
+const ENV_KEY_SEPARATOR: &str = "__";
+/// Unprefixed environment variables, only read in the absence of a config file
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("HOST", "server.host"),
+    ("PORT", "server.port"),
+    ("CONSUMER_KEY", "scraper.consumer_key"),
+    ("CONSUMER_SECRET", "scraper.consumer_secret"),
+    ("ACCESS_KEY", "scraper.access_key"),
+    ("ACCESS_SECRET", "scraper.access_secret"),
+    ("BATCH_SIZE", "scraper.batch_size"),
+];
+
+/// Config keys holding secrets, which can alternatively be read from a file (`<key>_file`)
+/// or from the output of a command (`<key>_command`)
+const SECRET_KEYS: &[(&str, &str)] = &[
+    ("server", "admin_token"),
+    ("scraper", "consumer_key"),
+    ("scraper", "consumer_secret"),
+    ("scraper", "access_key"),
+    ("scraper", "access_secret"),
+];
+
+/// Sensitive config value, which is redacted whenever it is printed or serialized
+#[derive(Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Placeholder shown instead of the secret, empty secrets are shown as such
+    fn placeholder(&self) -> &'static str {
+        if self.0.is_empty() {
+            ""
+        } else {
+            REDACTED
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret(secret.to_owned())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.placeholder())
+    }
+}
+
+impl serde::Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.placeholder())
+    }
+}
+
+/// Maximum length in bytes of a single track phrase of the Twitter streaming API
+pub const MAX_TOPIC_LENGTH: usize = 60;
+/// Maximum number of track phrases per Twitter streaming API connection
+pub const MAX_TOPICS: usize = 400;
+const MAX_BATCH_SIZE: usize = 10_000;
+
+/// Invalid value of a config key
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub key: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.reason)
+    }
+}
+
+/// Check whether a topic can be tracked by the Twitter streaming API
+pub fn validate_topic(topic: &str) -> Result<(), String> {
+    if topic.trim().is_empty() {
+        Err("Topic is empty".to_owned())
+    } else if topic.contains(',') {
         Err(format!(
-            "Could not read the config file: {file}. Reason: {error}",
-            file = config_path,
-            error = e
+            "Topic {} contains a comma, which separates track phrases",
+            topic
         ))
-    })?;
-    toml::from_str(&file_str).or_else(|e| {
+    } else if topic.len() > MAX_TOPIC_LENGTH {
         Err(format!(
-            "Unable to load config: {file}. Reason: {error}",
-            file = config_path,
-            error = e
+            "Topic {} exceeds the maximum length of {} bytes",
+            topic, MAX_TOPIC_LENGTH
         ))
-    })
+    } else {
+        Ok(())
+    }
 }
 
-fn number_from_env<T: std::str::FromStr>(label: &str) -> Result<T, String> {
-    std::env::var(label)
-        .map_err(|_| format!("No {} in environment variables", label))
-        .and_then(|x| str::parse::<T>(&x).map_err(|_| format!("Could not parse {} as u16", label)))
+/// Whether the server can listen on the host: an IPv4 address, a bracketed IPv6 address or a host name.
+/// Host names are only checked syntactically, so validation does not depend on DNS
+fn is_valid_host(host: &str) -> bool {
+    if host.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+    if let Some(address) = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        return address.parse::<Ipv6Addr>().is_ok();
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
 }
 
-pub fn from_env() -> Result<Config, String> {
-    let server_config = number_from_env::<u16>("PORT").map(|port| {
-        let host = std::env::var("HOST")
-            .map_err(|_| format!("No HOST in environment variables"))
-            .unwrap_or("0.0.0.0".to_owned());
+impl Config {
+    /// Check the configuration for problems which would only surface at runtime, reporting all of them at once.
+    /// API credentials can be omitted when tweets are not fetched from the Twitter API, e.g. when replaying
+    pub fn validate(&self, credentials_required: bool) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut problem = |key: &str, reason: String| {
+            problems.push(ConfigProblem {
+                key: key.to_owned(),
+                reason,
+            })
+        };
 
-        ServerConfig { host, port }
-    });
+        if !is_valid_host(&self.server.host) {
+            problem(
+                "server.host",
+                format!("{} is not a valid address to listen on", self.server.host),
+            );
+        }
+        if self
+            .server
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.is_empty())
+        {
+            problem("server.admin_token", "Token is empty".to_owned());
+        }
 
-    let scraper_config = std::env::var("CONSUMER_KEY")
-        .map_err(|_| format!("No CONSUMER_KEY in environment variables"))
-        .and_then(|consumer_key| {
-            std::env::var("CONSUMER_SECRET")
-                .map_err(|_| format!("No CONSUMER_SECRET in environment variables"))
-                .map(|consumer_secret| (consumer_key, consumer_secret))
-        })
-        .and_then(|(consumer_key, consumer_secret)| {
-            std::env::var("ACCESS_KEY")
-                .map_err(|_| format!("No ACCESS_KEY in environment variables"))
-                .map(|access_key| (consumer_key, consumer_secret, access_key))
-        })
-        .and_then(|(consumer_key, consumer_secret, access_key)| {
-            std::env::var("ACCESS_SECRET")
-                .map_err(|_| format!("No ACCESS_SECRET in environment variables"))
-                .map(|access_secret| (consumer_key, consumer_secret, access_key, access_secret))
-        })
-        .and_then(
-            |(consumer_key, consumer_secret, access_key, access_secret)| {
-                std::env::var("TOPICS")
-                    .map_err(|_| format!("No TOPICS in environment variables"))
-                    .map(|topics| topics.split(",").map(|x| x.to_owned()).collect())
-                    .map(|topics| ScraperConfig {
-                        consumer_key,
-                        consumer_secret,
-                        access_key,
-                        access_secret,
-                        topics,
-                        batch_size: number_from_env("BATCH_SIZE").ok(),
-                    })
-            },
-        );
+        let scraper = &self.scraper;
+        if credentials_required {
+            let credentials = [
+                ("scraper.consumer_key", &scraper.consumer_key),
+                ("scraper.consumer_secret", &scraper.consumer_secret),
+                ("scraper.access_key", &scraper.access_key),
+                ("scraper.access_secret", &scraper.access_secret),
+            ];
+            for (key, credential) in credentials.iter() {
+                if credential.expose().trim().is_empty() {
+                    problem(key, "Twitter API credential is empty".to_owned());
+                }
+            }
+        }
+
+        if scraper.topics.len() > MAX_TOPICS {
+            problem(
+                "scraper.topics",
+                format!("At most {} topics can be tracked", MAX_TOPICS),
+            );
+        }
+        let mut seen = HashSet::new();
+        for topic in &scraper.topics {
+            if let Err(reason) = validate_topic(topic) {
+                problem("scraper.topics", reason);
+            } else if !seen.insert(topic.trim().to_lowercase()) {
+                // Tracking is case insensitive
+                problem(
+                    "scraper.topics",
+                    format!("Topic {} is listed more than once", topic),
+                );
+            }
+        }
+
+        if let Some(batch_size) = scraper.batch_size {
+            if batch_size == 0 || batch_size > MAX_BATCH_SIZE {
+                problem(
+                    "scraper.batch_size",
+                    format!("{} is not between 1 and {}", batch_size, MAX_BATCH_SIZE),
+                );
+            }
+        }
 
-    server_config.and_then(|server_config| {
-        scraper_config.map(|scraper_config| Config {
-            scraper: scraper_config,
-            server: server_config,
+        if let Some(queue) = &scraper.queue {
+            if queue.capacity == Some(0) {
+                problem(
+                    "scraper.queue.capacity",
+                    "At least 1 tweet must be queued".to_owned(),
+                );
+            }
+            if queue.workers == Some(0) {
+                problem(
+                    "scraper.queue.workers",
+                    "At least 1 worker is required".to_owned(),
+                );
+            }
+        }
+
+        if let Some(shed_threshold) = scraper.shed_threshold {
+            if shed_threshold > 100 {
+                problem(
+                    "scraper.shed_threshold",
+                    format!("{}% is not a percentage", shed_threshold),
+                );
+            }
+        }
+
+        let retention = scraper
+            .retention
+            .iter()
+            .map(|retention| ("scraper.retention".to_owned(), retention))
+            .chain(scraper.overrides.iter().filter_map(|(topic, overrides)| {
+                overrides
+                    .retention
+                    .as_ref()
+                    .map(|retention| (format!("scraper.overrides.{}.retention", topic), retention))
+            }));
+        for (key, retention) in retention {
+            if retention.max_samples == Some(0) {
+                problem(
+                    &format!("{}.max_samples", key),
+                    "At least 1 sample must be kept".to_owned(),
+                );
+            }
+            if retention.max_age.is_some_and(|max_age| max_age <= 0) {
+                problem(
+                    &format!("{}.max_age", key),
+                    "Maximum age must be positive".to_owned(),
+                );
+            }
+        }
+
+        if let Some(reconnect) = &scraper.reconnect {
+            // Bounds which are left out are compared at their default
+            let policies = [
+                (
+                    "network_step_ms",
+                    reconnect.network_step_ms.unwrap_or(DEFAULT_NETWORK_STEP_MS),
+                    "network_max_ms",
+                    reconnect.network_max_ms.unwrap_or(DEFAULT_NETWORK_MAX_MS),
+                ),
+                (
+                    "http_initial_ms",
+                    reconnect.http_initial_ms.unwrap_or(DEFAULT_HTTP_INITIAL_MS),
+                    "http_max_ms",
+                    reconnect.http_max_ms.unwrap_or(DEFAULT_HTTP_MAX_MS),
+                ),
+                (
+                    "rate_limit_initial_ms",
+                    reconnect
+                        .rate_limit_initial_ms
+                        .unwrap_or(DEFAULT_RATE_LIMIT_INITIAL_MS),
+                    "rate_limit_max_ms",
+                    reconnect
+                        .rate_limit_max_ms
+                        .unwrap_or(DEFAULT_RATE_LIMIT_MAX_MS),
+                ),
+            ];
+            for (initial_key, initial, max_key, max) in policies.iter() {
+                if *initial == 0 {
+                    problem(
+                        &format!("scraper.reconnect.{}", initial_key),
+                        "Backoff must be positive".to_owned(),
+                    );
+                }
+                if *max == 0 {
+                    problem(
+                        &format!("scraper.reconnect.{}", max_key),
+                        "Backoff must be positive".to_owned(),
+                    );
+                } else if max < initial {
+                    problem(
+                        &format!("scraper.reconnect.{}", max_key),
+                        format!("{} is less than {} {}", max, initial_key, initial),
+                    );
+                }
+            }
+            if reconnect.idle_timeout_ms == Some(0) {
+                problem(
+                    "scraper.reconnect.idle_timeout_ms",
+                    "Idle timeout must be positive".to_owned(),
+                );
+            }
+            if let Some(jitter) = reconnect.jitter {
+                if !(0.0..=1.0).contains(&jitter) {
+                    problem(
+                        "scraper.reconnect.jitter",
+                        format!("{} is not between 0 and 1", jitter),
+                    );
+                }
+            }
+        }
+
+        if let Some(record) = &scraper.record {
+            if record.directory.trim().is_empty() {
+                problem("scraper.record.directory", "Directory is empty".to_owned());
+            }
+        }
+        if let Some(StorageConfig::Segment { directory }) = &self.storage {
+            if directory.trim().is_empty() {
+                problem("storage.directory", "Directory is empty".to_owned());
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Validation(problems))
+        }
+    }
+}
+
+/// Reasons a configuration could not be assembled
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Io { path: String, source: io::Error },
+    /// The config file is not valid TOML, or holds a value of the wrong type
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    /// An override targets a key nested in a value which is not a table
+    Override { origin: String, key: String },
+    /// A secret could not be resolved from its file or command
+    Secret { key: String, reason: String },
+    /// The merged configuration does not match the expected structure
+    Invalid(toml::de::Error),
+    /// The configuration is structurally valid, but contains invalid values
+    Validation(Vec<ConfigProblem>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "Could not read config file {}: {}", path, source)
+            }
+            // The TOML error already reports the line & column of the problem
+            ConfigError::Parse { path, source } => {
+                write!(f, "Invalid config file {}: {}", path, source)
+            }
+            ConfigError::Override { origin, key } => {
+                write!(
+                    f,
+                    "{} overrides key {}, which is not part of a table",
+                    origin, key
+                )
+            }
+            ConfigError::Secret { key, reason } => {
+                write!(f, "Could not resolve secret {}: {}", key, reason)
+            }
+            ConfigError::Invalid(source) => write!(f, "Invalid configuration: {}", source),
+            ConfigError::Validation(problems) => {
+                write!(f, "Invalid configuration:")?;
+                problems
+                    .iter()
+                    .try_for_each(|problem| write!(f, "\n  - {}", problem))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } | ConfigError::Invalid(source) => Some(source),
+            ConfigError::Override { .. }
+            | ConfigError::Secret { .. }
+            | ConfigError::Validation(_) => None,
+        }
+    }
+}
+
+/// Assemble the configuration from its layers, in increasing order of precedence:
+/// * The config file, if any
+/// * Unprefixed environment variables (e.g. `PORT`), only in the absence of a config file
+/// * `SIBYL_*` environment variables, e.g. `SIBYL_SERVER__PORT` overrides `server.port`
+/// * Command line overrides, as `(key, value)` pairs
+///
+/// Afterwards, secrets are read from their `<key>_file` or `<key>_command`, if specified
+pub fn load(
+    config_path: Option<&str>,
+    cli_overrides: &[(String, Value)],
+) -> Result<Config, ConfigError> {
+    let file = config_path
+        .map(|path| read_file(path).map(|text| (path, text)))
+        .transpose()?;
+    let layer = file
+        .as_ref()
+        .map(|(path, text)| {
+            text.parse::<Value>().map_err(|source| ConfigError::Parse {
+                path: (*path).to_owned(),
+                source,
+            })
         })
+        .transpose()?;
+    merge_layers(layer, std::env::vars(), cli_overrides).map_err(|err| match (err, &file) {
+        (ConfigError::Invalid(source), Some((path, text))) => locate(path, text, source),
+        (err, _) => err,
     })
 }
+
+fn read_file(config_path: &str) -> Result<String, ConfigError> {
+    let mut file_str = String::new();
+    File::open(Path::new(config_path))
+        .and_then(|mut file| file.read_to_string(&mut file_str))
+        .map_err(|source| ConfigError::Io {
+            path: config_path.to_owned(),
+            source,
+        })?;
+    Ok(file_str)
+}
+
+/// Errors of the merged configuration carry no location, as the layers are merged as values.
+/// When the offending value stems from the config file, deserializing the file on its own
+/// reports the same error along with its location
+fn locate(path: &str, text: &str, source: toml::de::Error) -> ConfigError {
+    match toml::from_str::<Config>(text) {
+        Err(located)
+            if located.line_col().is_some()
+                && located.to_string().starts_with(&source.to_string()) =>
+        {
+            ConfigError::Parse {
+                path: path.to_owned(),
+                source: located,
+            }
+        }
+        _ => ConfigError::Invalid(source),
+    }
+}
+
+fn merge_layers<E: IntoIterator<Item = (String, String)>>(
+    file: Option<Value>,
+    env: E,
+    cli_overrides: &[(String, Value)],
+) -> Result<Config, ConfigError> {
+    let legacy = file.is_none();
+    let mut config = file.unwrap_or_else(|| Value::Table(Table::new()));
+
+    // Apply environment variables in a deterministic order
+    let mut env = env.into_iter().collect::<Vec<_>>();
+    env.sort();
+    if legacy {
+        for (name, value) in &env {
+            if name == "TOPICS" {
+                let topics = value
+                    .split(',')
+                    .map(|topic| Value::String(topic.to_owned()));
+                set_key(
+                    &mut config,
+                    "scraper.topics",
+                    Value::Array(topics.collect()),
+                    name,
+                )?
+            }
+            if let Some((_, key)) = LEGACY_ENV.iter().find(|(legacy, _)| legacy == name) {
+                set_key(&mut config, key, env_value(key, value), name)?;
+            }
+        }
+    }
+    for (name, value) in &env {
+        if let Some(path) = name.strip_prefix(ENV_PREFIX) {
+            let key = path.to_lowercase().replace(ENV_KEY_SEPARATOR, ".");
+            set_key(&mut config, &key, env_value(&key, value), name)?;
+        }
+    }
+
+    for (key, value) in cli_overrides {
+        set_key(&mut config, key, value.clone(), "Command line")?;
+    }
+    resolve_secrets(&mut config)?;
+    config.try_into().map_err(ConfigError::Invalid)
+}
+
+/// Replace secret files & commands by the secrets they provide.
+/// At most one source can be specified per secret
+fn resolve_secrets(config: &mut Value) -> Result<(), ConfigError> {
+    for (section, name) in SECRET_KEYS {
+        let table = match config.get_mut(section).and_then(Value::as_table_mut) {
+            Some(table) => table,
+            None => continue,
+        };
+        let key = format!("{}.{}", section, name);
+        let error = |reason: String| ConfigError::Secret {
+            key: key.clone(),
+            reason,
+        };
+        let file = table.remove(&format!("{}_file", name));
+        let command = table.remove(&format!("{}_command", name));
+        // Empty inline values are placeholders, e.g. in the example config
+        let inline = table
+            .get(*name)
+            .is_some_and(|value| value.as_str() != Some(""));
+        let secret = match (inline, file, command) {
+            (_, None, None) => continue,
+            (false, Some(file), None) => {
+                let path = file
+                    .as_str()
+                    .ok_or_else(|| error("Secret file is not a path".to_owned()))?;
+                fs::read_to_string(path)
+                    .map_err(|err| error(format!("Could not read {}: {}", path, err)))?
+            }
+            (false, None, Some(command)) => {
+                let command = command
+                    .as_str()
+                    .ok_or_else(|| error("Secret command is not a string".to_owned()))?;
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .map_err(|err| error(format!("Could not run command: {}", err)))?;
+                if !output.status.success() {
+                    return Err(error(format!(
+                        "Command failed with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| error("Command output is not valid UTF-8".to_owned()))?
+            }
+            _ => {
+                return Err(error(
+                    "Only one of the value, its file or its command can be specified".to_owned(),
+                ))
+            }
+        };
+        // Secret files & command output typically end with a newline
+        table.insert(
+            (*name).to_owned(),
+            Value::String(secret.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+        );
+    }
+    Ok(())
+}
+
+/// Value of an environment variable setting the dotted key.
+/// Secrets are taken verbatim, so e.g. a numeric secret is not interpreted as an integer
+fn env_value(key: &str, raw: &str) -> Value {
+    if is_secret(key) {
+        Value::String(raw.to_owned())
+    } else {
+        parse_value(raw)
+    }
+}
+
+/// Whether the dotted key holds a secret
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS
+        .iter()
+        .any(|(section, name)| key.split_once('.') == Some((section, name)))
+}
+
+/// Interpret a raw override value as a TOML value, falling back to a plain string.
+/// Quoting forces a string, e.g. `"8080"`
+pub fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .filter(|table| table.len() == 1)
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+/// Set a dotted key, creating any missing intermediate tables
+fn set_key(config: &mut Value, key: &str, value: Value, origin: &str) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::Override {
+        origin: origin.to_owned(),
+        key: key.to_owned(),
+    };
+    let mut segments = key.split('.').collect::<Vec<_>>();
+    let last = segments
+        .pop()
+        .filter(|last| !last.is_empty())
+        .ok_or_else(invalid)?;
+    let mut table = config.as_table_mut().ok_or_else(invalid)?;
+    for segment in segments {
+        table = table
+            .entry(segment.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(invalid)?;
+    }
+    table.insert(last.to_owned(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn table(toml: &str) -> Value {
+        toml.parse::<Value>().expect("Invalid TOML")
+    }
+
+    #[test]
+    fn report_parse_error_location() {
+        let path =
+            std::env::temp_dir().join(format!("sibyl-invalid-config-{}.toml", std::process::id()));
+        File::create(&path)
+            .and_then(|mut file| {
+                file.write_all(b"[server]\nhost = \"0.0.0.0\"\nport = \"eighty\"\n")
+            })
+            .expect("Could not write config file");
+
+        let err = match load(Some(&path.to_string_lossy()), &[]) {
+            Err(err @ ConfigError::Parse { .. }) => err,
+            Err(other) => panic!("Expected a parse error, got {}", other),
+            Ok(_) => panic!("Invalid config should not load"),
+        };
+        assert!(
+            err.to_string().contains("at line 3"),
+            "Location missing from: {}",
+            err
+        );
+
+        match load(Some("/nonexistent/sibyl.toml"), &[]) {
+            Err(ConfigError::Io { .. }) => (),
+            Err(other) => panic!("Expected an I/O error, got {}", other),
+            Ok(_) => panic!("Missing config should not load"),
+        }
+    }
+
+    #[test]
+    fn merge_config_layers() {
+        let file = table(include_str!("../cfg/full.toml"));
+        let env = vec![
+            ("PORT".to_owned(), "1".to_owned()),
+            ("SIBYL_SERVER__PORT".to_owned(), "8080".to_owned()),
+            ("SIBYL_SCRAPER__BATCH_SIZE".to_owned(), "10".to_owned()),
+            ("SIBYL_SCRAPER__CONSUMER_KEY".to_owned(), "123".to_owned()),
+            (
+                "SIBYL_SCRAPER__CONSUMER_SECRET".to_owned(),
+                "012345".to_owned(),
+            ),
+            ("SIBYL_SERVER__HOST".to_owned(), "localhost".to_owned()),
+        ];
+        let cli = vec![("scraper.batch_size".to_owned(), parse_value("20"))];
+
+        let config = merge_layers(Some(file), env, &cli).expect("Could not merge layers");
+        assert_eq!(
+            config.server.port, 8080,
+            "Legacy variables only apply without a file"
+        );
+        assert_eq!(config.server.host, "localhost");
+        assert_eq!(
+            config.scraper.consumer_key.expose(),
+            "123",
+            "Secrets are never interpreted"
+        );
+        assert_eq!(config.scraper.consumer_secret.expose(), "012345");
+        assert_eq!(config.scraper.batch_size, Some(20), "CLI takes precedence");
+        assert!(config.scraper.topics.contains(&"music".to_owned()));
+        assert!(
+            !config.to_string().contains("123"),
+            "Secrets should be redacted"
+        );
+
+        let env = vec![("SIBYL_SERVER__PORT__NUMBER".to_owned(), "1".to_owned())];
+        match merge_layers(Some(table("[server]\nport = 1")), env, &[]) {
+            Err(ConfigError::Override { key, .. }) => assert_eq!(key, "server.port.number"),
+            Err(other) => panic!("Expected an override error, got {}", other),
+            Ok(_) => panic!("Nested key of an integer should not be set"),
+        }
+    }
+
+    #[test]
+    fn merge_legacy_env() {
+        let env = vec![
+            ("PORT", "80"),
+            ("CONSUMER_KEY", "123"),
+            ("CONSUMER_SECRET", "cs"),
+            ("ACCESS_KEY", "ak"),
+            ("ACCESS_SECRET", "as"),
+            ("TOPICS", "music,food"),
+            ("STORAGE_DIRECTORY", "/tmp/sibyl"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect::<Vec<_>>();
+
+        let config = merge_layers(None, env, &[]).expect("Could not merge layers");
+        assert_eq!(config.server.port, 80);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.scraper.topics, vec!["music", "food"]);
+        assert_eq!(
+            config.scraper.consumer_key.expose(),
+            "123",
+            "Secrets are never interpreted"
+        );
+        assert!(
+            config.storage.is_none(),
+            "Settings without a legacy variable are only read from SIBYL_* variables"
+        );
+    }
+
+    #[test]
+    fn validate_topics() {
+        assert!(validate_topic("rust lang").is_ok());
+        assert!(validate_topic(&"ü".repeat(MAX_TOPIC_LENGTH / 2)).is_ok());
+        assert!(
+            validate_topic(&"ü".repeat(MAX_TOPIC_LENGTH / 2 + 1)).is_err(),
+            "The length limit applies to bytes, not characters"
+        );
+        assert!(validate_topic("music,food").is_err());
+    }
+
+    #[test]
+    fn validate_host() {
+        for host in &[
+            "0.0.0.0",
+            "[::1]",
+            "localhost",
+            "api.example.com.",
+            "my-host",
+        ] {
+            assert!(is_valid_host(host), "{} should be valid", host);
+        }
+        for host in &["", "not a host", "::1", "-host", "a..b", "host_name"] {
+            assert!(!is_valid_host(host), "{} should be invalid", host);
+        }
+    }
+
+    #[test]
+    fn report_all_problems() {
+        let mut config = merge_layers(Some(table(include_str!("../cfg/full.toml"))), vec![], &[])
+            .expect("Could not load example config");
+        assert!(
+            config.validate(false).is_ok(),
+            "Example config should be valid"
+        );
+
+        config.server.host = "not a host".to_owned();
+        config.scraper.topics = vec![
+            "music".to_owned(),
+            " ".to_owned(),
+            "Music".to_owned(),
+            "x".repeat(MAX_TOPIC_LENGTH + 1),
+        ];
+        config.scraper.batch_size = Some(0);
+        config.scraper.reconnect = Some(ReconnectConfig {
+            network_max_ms: Some(0),
+            http_max_ms: Some(4_000),
+            jitter: Some(1.5),
+            ..ReconnectConfig::default()
+        });
+        let problems = match config.validate(true) {
+            Err(ConfigError::Validation(problems)) => problems,
+            other => panic!("Expected validation problems, got {:?}", other),
+        };
+        let keys = problems
+            .iter()
+            .map(|problem| problem.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                "server.host",
+                "scraper.consumer_key",
+                "scraper.consumer_secret",
+                "scraper.access_key",
+                "scraper.access_secret",
+                "scraper.topics",
+                "scraper.topics",
+                "scraper.topics",
+                "scraper.batch_size",
+                "scraper.reconnect.network_max_ms",
+                "scraper.reconnect.http_max_ms",
+                "scraper.reconnect.jitter",
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_without_credentials() {
+        let file = table("[server]\nport = 80\n[scraper]\ntopics = [\"music\"]");
+        let config = merge_layers(Some(file), vec![], &[]).expect("Could not merge layers");
+        assert!(config.validate(false).is_ok());
+        assert!(
+            config.validate(true).is_err(),
+            "Credentials are required for the Twitter API"
+        );
+    }
+
+    #[test]
+    fn resolve_secret_sources() {
+        let path =
+            std::env::temp_dir().join(format!("sibyl-consumer-secret-{}", std::process::id()));
+        fs::write(&path, "file-secret\n").expect("Could not write secret file");
+        let file = table(&format!(
+            "[server]\nport = 80\nadmin_token_command = \"echo command-secret\"\n\
+             [scraper]\nconsumer_key = \"key\"\nconsumer_secret_file = {:?}\n\
+             access_key = \"\"\naccess_secret = \"\"\ntopics = []",
+            path
+        ));
+
+        let config =
+            merge_layers(Some(file.clone()), vec![], &[]).expect("Could not resolve secrets");
+        assert_eq!(config.scraper.consumer_secret.expose(), "file-secret");
+        assert_eq!(
+            config.server.admin_token.as_ref().map(Secret::expose),
+            Some("command-secret")
+        );
+        assert_eq!(
+            format!("{:?}", config.server.admin_token),
+            "Some(\"<redacted>\")"
+        );
+
+        let env = vec![(
+            "SIBYL_SCRAPER__CONSUMER_SECRET".to_owned(),
+            "inline".to_owned(),
+        )];
+        match merge_layers(Some(file), env, &[]) {
+            Err(ConfigError::Secret { key, .. }) => assert_eq!(key, "scraper.consumer_secret"),
+            Err(other) => panic!("Expected a secret error, got {}", other),
+            Ok(_) => panic!("Conflicting secret sources should be rejected"),
+        }
+    }
+}
@@ -1,14 +1,30 @@
+use crate::scraper::replay::{Pacing, ReplaySource};
 use crate::scraper::Scraper;
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use toml::Value;
 
-use log::Level;
+use log::{error, Level};
 
 mod config;
+mod reload;
 mod scraper;
 mod server;
 mod tweet;
 
-fn cmd_line_config() -> Option<String> {
+struct CmdLine {
+    config: Option<String>,
+    /// Config keys overridden on the command line
+    overrides: Vec<(String, Value)>,
+    print_config: bool,
+    /// Only validate the configuration
+    check_config: bool,
+    replay: Option<(String, Pacing)>,
+}
+
+fn cmd_line_config() -> CmdLine {
     let matches = App::new("twitter-sibyl-system")
         .version("0.1")
         .about("Real-time sentiment analysis for twitter topic streams")
@@ -20,9 +36,115 @@ fn cmd_line_config() -> Option<String> {
                 .takes_value(true)
                 .help("Configuration file"),
         )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .help("Address the HTTP server will listen on"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .help("Port the HTTP server will listen on"),
+        )
+        .arg(
+            Arg::with_name("topics")
+                .long("topics")
+                .value_name("topic,...")
+                .takes_value(true)
+                .help("Comma separated list of topics to fetch & process new tweets for"),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Override a config key, e.g. scraper.batch_size=50"),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Print the effective configuration, with secrets redacted, and exit"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("dump-file")
+                .takes_value(true)
+                .help(
+                    "Replay tweets from a newline-delimited JSON dump instead of the Twitter API",
+                ),
+        )
+        .arg(
+            Arg::with_name("replay-pacing")
+                .long("replay-pacing")
+                .takes_value(true)
+                .possible_values(&["original", "fast"])
+                .requires("replay")
+                .help(
+                    "Honour the original tweet intervals (default) or replay as fast as possible",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validate the configuration, reporting all problems at once")
+                .arg(
+                    Arg::with_name("config")
+                        .short("c")
+                        .value_name("config-file")
+                        .takes_value(true)
+                        .help("Configuration file"),
+                ),
+        )
         .get_matches();
+    let check_config = matches.subcommand_matches("check-config");
+
+    let pacing = match matches.value_of("replay-pacing") {
+        Some("fast") => Pacing::Fast,
+        _ => Pacing::Original,
+    };
+    let mut overrides = Vec::new();
+    if let Some(host) = matches.value_of("host") {
+        overrides.push(("server.host".to_owned(), Value::String(host.to_owned())));
+    }
+    if let Some(port) = matches.value_of("port") {
+        overrides.push(("server.port".to_owned(), config::parse_value(port)));
+    }
+    if let Some(topics) = matches.value_of("topics") {
+        let topics = topics
+            .split(',')
+            .map(|topic| Value::String(topic.trim().to_owned()))
+            .collect();
+        overrides.push(("scraper.topics".to_owned(), Value::Array(topics)));
+    }
+    for assignment in matches.values_of("set").into_iter().flatten() {
+        match assignment.find('=') {
+            Some(index) => overrides.push((
+                assignment[..index].trim().to_owned(),
+                config::parse_value(assignment[index + 1..].trim()),
+            )),
+            None => {
+                error!("Invalid config override {}, expected key=value", assignment);
+                process::exit(1)
+            }
+        }
+    }
 
-    matches.value_of("config").map(|x| x.trim().to_owned())
+    CmdLine {
+        config: check_config
+            .and_then(|check| check.value_of("config"))
+            .or_else(|| matches.value_of("config"))
+            .map(|x| x.trim().to_owned()),
+        overrides,
+        print_config: matches.is_present("print-config"),
+        check_config: check_config.is_some(),
+        replay: matches
+            .value_of("replay")
+            .map(|x| (x.trim().to_owned(), pacing)),
+    }
 }
 
 fn main() -> std::io::Result<()> {
@@ -31,22 +153,75 @@ fn main() -> std::io::Result<()> {
         .expect("Could not initialize the logging framework");
 
     // Fetch configuration
-    let config_uri = cmd_line_config();
-    let config = config_uri
-        .and_then(|cfg_uri| config::load_config(&cfg_uri).ok())
-        .or_else(|| config::from_env().ok())
-        .expect("Could not assemble a valid configuration");
+    let cmd_line = cmd_line_config();
+    let config =
+        config::load(cmd_line.config.as_deref(), &cmd_line.overrides).unwrap_or_else(|err| {
+            error!("Could not assemble a valid configuration: {}", err);
+            process::exit(1)
+        });
+    if cmd_line.print_config {
+        print!("{}", config);
+        return Ok(());
+    }
+    if cmd_line.check_config {
+        match config.validate(true) {
+            Ok(()) => println!("Configuration is valid"),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1)
+            }
+        }
+        return Ok(());
+    }
+    // Replayed tweets do not require API credentials
+    let credentials_required = cmd_line.replay.is_none();
+    if let Err(err) = config.validate(credentials_required) {
+        error!("{}", err);
+        process::exit(1)
+    }
+
+    // Initialize storage
+    let storage = scraper::storage::from_config(config.storage.as_ref()).unwrap_or_else(|err| {
+        error!("Could not initialize time series storage: {}", err);
+        process::exit(1)
+    });
 
     // Initialize Scraper
-    let scraper = Scraper::new(config.scraper);
+    let scraper = Arc::new(match cmd_line.replay {
+        Some((dump, pacing)) => Scraper::with_source(
+            config.scraper.clone(),
+            Arc::new(ReplaySource::new(dump, pacing)),
+            storage,
+        ),
+        None => Scraper::new(config.scraper.clone(), storage),
+    });
 
     // Initialize actix runtime
     let actor_system = actix_rt::System::new("webservice");
 
     // Initialize server
-    server::run(config.server, scraper.time_series(), scraper.metrics())
-        .expect("Could not start server");
+    let shutdown_deadline = server::watch_shutdown(Duration::from_secs(
+        config
+            .server
+            .shutdown_timeout
+            .unwrap_or(server::DEFAULT_SHUTDOWN_TIMEOUT),
+    ));
+    server::run(config.server.clone(), scraper.clone()).expect("Could not start server");
+
+    // Apply configuration changes without restarting
+    reload::watch(Arc::new(reload::Reloader::new(
+        cmd_line.config,
+        cmd_line.overrides,
+        credentials_required,
+        config,
+        scraper.clone(),
+    )));
 
     // Block until actor system has stopped
-    actor_system.run()
+    let result = actor_system.run();
+
+    // Drain the in-flight tweets & persist any pending time series samples,
+    // within the time the server left of the shutdown timeout
+    scraper.shutdown(shutdown_deadline.remaining());
+    result
 }
@@ -0,0 +1,119 @@
+use crate::config::{self, Config};
+use crate::scraper::Scraper;
+use futures::{Future, Stream};
+use log::{error, info, warn};
+use metrics_runtime::data::Counter;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_timer::Interval;
+use toml::Value;
+
+/// Interval at which the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Re-assembles the configuration & applies it to the running scraper
+pub struct Reloader {
+    config_path: Option<String>,
+    overrides: Vec<(String, Value)>,
+    credentials_required: bool,
+    current: Mutex<Config>,
+    scraper: Arc<Scraper>,
+    succeeded: Counter,
+    failed: Counter,
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: Option<String>,
+        overrides: Vec<(String, Value)>,
+        credentials_required: bool,
+        current: Config,
+        scraper: Arc<Scraper>,
+    ) -> Self {
+        let mut sink = scraper.metrics_sink();
+        Self {
+            config_path,
+            overrides,
+            credentials_required,
+            current: Mutex::new(current),
+            succeeded: sink.counter_with_labels("config_reloads", &[("outcome", "success")]),
+            failed: sink.counter_with_labels("config_reloads", &[("outcome", "failure")]),
+            scraper,
+        }
+    }
+
+    /// Reload the configuration from all its layers.
+    /// An invalid configuration is rejected, in which case the running configuration is kept
+    pub fn reload(&self) {
+        let config = config::load(self.config_path.as_deref(), &self.overrides)
+            .and_then(|config| config.validate(self.credentials_required).map(|()| config));
+        let config = match config {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    "Could not reload configuration, keeping the running configuration: {}",
+                    err
+                );
+                self.failed.record(1);
+                return;
+            }
+        };
+
+        let mut current = self.current.lock().expect("Config lock was poisoned");
+        if config.server != current.server {
+            warn!("Changed server configuration only takes effect after a restart");
+        }
+        if config.storage != current.storage {
+            warn!("Changed storage configuration only takes effect after a restart");
+        }
+        self.scraper.reconfigure(config.scraper.clone());
+        *current = config;
+        self.succeeded.record(1);
+        info!("Reloaded configuration");
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reload the configuration on SIGHUP & whenever the config file is modified.
+/// Must be called from within the actix system
+pub fn watch(reloader: Arc<Reloader>) {
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGHUP};
+
+        let on_hangup = reloader.clone();
+        actix_rt::spawn(
+            Signal::new(SIGHUP)
+                .flatten_stream()
+                .map_err(|err| error!("Could not listen for SIGHUP: {}", err))
+                .for_each(move |_| {
+                    info!("Received SIGHUP, reloading configuration");
+                    on_hangup.reload();
+                    Ok(())
+                }),
+        );
+    }
+
+    if let Some(path) = reloader.config_path.clone() {
+        let mut modified = modified_time(&path);
+        actix_rt::spawn(
+            Interval::new_interval(WATCH_INTERVAL)
+                .map_err(|err| error!("Config file watch timer failed: {}", err))
+                .for_each(move |_| {
+                    let latest = modified_time(&path);
+                    if latest != modified {
+                        modified = latest;
+                        info!("Config file {} changed, reloading configuration", path);
+                        reloader.reload();
+                    }
+                    Ok(())
+                }),
+        );
+    }
+}
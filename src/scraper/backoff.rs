@@ -0,0 +1,186 @@
+use crate::config::{
+    ReconnectConfig, DEFAULT_HTTP_INITIAL_MS, DEFAULT_HTTP_MAX_MS, DEFAULT_JITTER,
+    DEFAULT_NETWORK_MAX_MS, DEFAULT_NETWORK_STEP_MS, DEFAULT_RATE_LIMIT_INITIAL_MS,
+    DEFAULT_RATE_LIMIT_MAX_MS,
+};
+use rand::Rng;
+use std::cmp::min;
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+
+/// Kinds of connection failures, each with their own reconnection strategy
+/// c.f.r. https://developer.twitter.com/en/docs/tweets/filter-realtime/guides/connecting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// TCP/IP level errors, backing off linearly
+    Network,
+    /// HTTP errors, backing off exponentially
+    Http,
+    /// Rate limiting (420/429), backing off exponentially from a larger initial delay
+    RateLimited,
+}
+
+/// Delays between reconnection attempts, escalating on consecutive failures of the same kind.
+/// Shared by consecutive connections, so reopening the connection does not reset the backoff
+pub struct Backoff {
+    network_step: Duration,
+    network_max: Duration,
+    http_initial: Duration,
+    http_max: Duration,
+    rate_limit_initial: Duration,
+    rate_limit_max: Duration,
+    jitter: f64,
+    /// Kind of the previous failure along with its delay, before jitter
+    previous: Option<(Failure, Duration)>,
+    /// Time until which connecting is backed off
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        let millis = |value: Option<u64>, default| Duration::from_millis(value.unwrap_or(default));
+        Self {
+            network_step: millis(config.network_step_ms, DEFAULT_NETWORK_STEP_MS),
+            network_max: millis(config.network_max_ms, DEFAULT_NETWORK_MAX_MS),
+            http_initial: millis(config.http_initial_ms, DEFAULT_HTTP_INITIAL_MS),
+            http_max: millis(config.http_max_ms, DEFAULT_HTTP_MAX_MS),
+            rate_limit_initial: millis(config.rate_limit_initial_ms, DEFAULT_RATE_LIMIT_INITIAL_MS),
+            rate_limit_max: millis(config.rate_limit_max_ms, DEFAULT_RATE_LIMIT_MAX_MS),
+            jitter: config.jitter.unwrap_or(DEFAULT_JITTER).clamp(0.0, 1.0),
+            previous: None,
+            retry_at: None,
+        }
+    }
+
+    /// Delay before reconnecting after the failure.
+    /// Jitter randomly shortens the delay, so reconnecting clients do not retry in lockstep
+    pub fn next_delay(&mut self, failure: Failure) -> Duration {
+        let previous = self
+            .previous
+            .filter(|(kind, _)| *kind == failure)
+            .map(|(_, delay)| delay);
+        let delay = match failure {
+            Failure::Network => min(
+                previous.map_or(self.network_step, |delay| delay + self.network_step),
+                self.network_max,
+            ),
+            Failure::Http => min(
+                previous.map_or(self.http_initial, |delay| delay * 2),
+                self.http_max,
+            ),
+            Failure::RateLimited => min(
+                previous.map_or(self.rate_limit_initial, |delay| delay * 2),
+                self.rate_limit_max,
+            ),
+        };
+        self.previous = Some((failure, delay));
+        delay.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+    }
+
+    /// Timer completing once the connection may be re-established, along with its delay
+    pub fn wait(&mut self, failure: Failure) -> (Duration, Delay) {
+        let delay = self.next_delay(failure);
+        let retry_at = clock::now() + delay;
+        self.retry_at = Some(retry_at);
+        (delay, Delay::new(retry_at))
+    }
+
+    /// Timer completing once a new connection may be opened, along with its remaining delay,
+    /// when a previous connection is still backing off
+    pub fn pending(&self) -> Option<(Duration, Delay)> {
+        let now = clock::now();
+        self.retry_at
+            .filter(|&retry_at| retry_at > now)
+            .map(|retry_at| (retry_at - now, Delay::new(retry_at)))
+    }
+
+    /// Start over from the initial delays, once the connection delivers data again
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tokio_timer::clock::{Clock, Now};
+
+    struct FixedNow(Instant);
+
+    impl Now for FixedNow {
+        fn now(&self) -> Instant {
+            self.0
+        }
+    }
+
+    fn without_jitter() -> Backoff {
+        Backoff::new(&ReconnectConfig {
+            jitter: Some(0.0),
+            ..ReconnectConfig::default()
+        })
+    }
+
+    fn delays(backoff: &mut Backoff, failure: Failure, attempts: usize) -> Vec<u64> {
+        (0..attempts)
+            .map(|_| backoff.next_delay(failure).as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn escalate_per_failure_kind() {
+        let mut backoff = without_jitter();
+        assert_eq!(
+            delays(&mut backoff, Failure::Network, 3),
+            vec![250, 500, 750]
+        );
+        assert_eq!(
+            delays(&mut backoff, Failure::Http, 8),
+            vec![5_000, 10_000, 20_000, 40_000, 80_000, 160_000, 320_000, 320_000]
+        );
+        assert_eq!(
+            delays(&mut backoff, Failure::RateLimited, 2),
+            vec![60_000, 120_000],
+            "A different kind of failure starts from its initial delay"
+        );
+        backoff.reset();
+        assert_eq!(delays(&mut backoff, Failure::RateLimited, 1), vec![60_000]);
+
+        let mut backoff = without_jitter();
+        assert_eq!(
+            delays(&mut backoff, Failure::Network, 70).last(),
+            Some(&16_000),
+            "Network backoff is capped"
+        );
+    }
+
+    #[test]
+    fn jitter_shortens_delays() {
+        let mut backoff = Backoff::new(&ReconnectConfig {
+            jitter: Some(0.5),
+            ..ReconnectConfig::default()
+        });
+        for _ in 0..100 {
+            let delay = backoff.next_delay(Failure::Http);
+            backoff.reset();
+            assert!(delay <= Duration::from_millis(5_000));
+            assert!(delay >= Duration::from_millis(2_500));
+        }
+    }
+
+    #[test]
+    fn wait_from_current_time() {
+        let now = Instant::now() + Duration::from_secs(3600);
+        let _clock = clock::set_default(&Clock::new_with_now(FixedNow(now)));
+        let mut backoff = without_jitter();
+        let (delay, waiter) = backoff.wait(Failure::RateLimited);
+        assert_eq!(delay, Duration::from_secs(60));
+        assert_eq!(waiter.deadline(), now + delay);
+        let (remaining, waiter) = backoff.pending().expect("Connecting should be backed off");
+        assert_eq!(remaining, delay);
+        assert_eq!(waiter.deadline(), now + delay);
+        backoff.reset();
+        assert!(backoff.pending().is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use futures::stream::Fuse;
+use futures::{Async, Poll, Stream};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Stream grouping the items of its inner stream into batches of at most the current batch size.
+/// Unlike `Stream::chunks`, a partial batch is emitted as soon as no more items are ready,
+/// and the batch size can be changed while the stream is running
+pub struct Batches<S: Stream> {
+    inner: Fuse<S>,
+    batch_size: Arc<AtomicUsize>,
+    items: Vec<S::Item>,
+    /// Error of the inner stream, reported after the pending batch
+    err: Option<S::Error>,
+}
+
+pub fn batches<S: Stream>(inner: S, batch_size: Arc<AtomicUsize>) -> Batches<S> {
+    Batches {
+        inner: inner.fuse(),
+        batch_size,
+        items: Vec::new(),
+        err: None,
+    }
+}
+
+impl<S: Stream> Stream for Batches<S> {
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(err) = self.err.take() {
+            return Err(err);
+        }
+        loop {
+            match self.inner.poll() {
+                Ok(Async::Ready(Some(item))) => {
+                    self.items.push(item);
+                    if self.items.len() >= self.batch_size.load(Ordering::Relaxed).max(1) {
+                        return Ok(Async::Ready(Some(mem::take(&mut self.items))));
+                    }
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) if !self.items.is_empty() => {
+                    return Ok(Async::Ready(Some(mem::take(&mut self.items))));
+                }
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) if !self.items.is_empty() => {
+                    self.err = Some(err);
+                    return Ok(Async::Ready(Some(mem::take(&mut self.items))));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn resize_batches() {
+        let batch_size = Arc::new(AtomicUsize::new(2));
+        let mut batched = batches(stream::iter_ok::<_, ()>(1..=6), batch_size.clone()).wait();
+        assert_eq!(batched.next(), Some(Ok(vec![1, 2])));
+        batch_size.store(3, Ordering::Relaxed);
+        assert_eq!(batched.next(), Some(Ok(vec![3, 4, 5])));
+        assert_eq!(
+            batched.next(),
+            Some(Ok(vec![6])),
+            "Partial batches are emitted"
+        );
+        assert_eq!(batched.next(), None);
+    }
+}
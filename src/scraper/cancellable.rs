@@ -0,0 +1,44 @@
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
+
+/// Signal shared by all tasks of a subscription.
+/// It fires when its sender is either used or dropped
+pub type CancelSignal = Shared<oneshot::Receiver<()>>;
+
+/// Stream which ends as soon as its cancel signal fires
+pub struct Cancellable<S> {
+    inner: S,
+    signal: CancelSignal,
+}
+
+pub fn cancellable<S: Stream>(inner: S, signal: CancelSignal) -> Cancellable<S> {
+    Cancellable { inner, signal }
+}
+
+impl<S: Stream> Stream for Cancellable<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.signal.poll() {
+            Ok(Async::NotReady) => self.inner.poll(),
+            Ok(Async::Ready(_)) | Err(_) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn end_stream_on_cancel() {
+        let (cancel, signal) = oneshot::channel::<()>();
+        let mut cancellable = cancellable(stream::repeat::<_, ()>(1), signal.shared()).wait();
+        assert_eq!(cancellable.next(), Some(Ok(1)));
+        drop(cancel);
+        assert_eq!(cancellable.next(), None);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::scraper::metrics::Sample;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Number of statuses which are tracked for deletion
+const RECENT_STATUSES: usize = 100_000;
+
+struct Index {
+    samples: HashMap<u64, Sample>,
+    /// Status ids in the order they were scored
+    order: VecDeque<u64>,
+}
+
+/// Samples of the most recently scored statuses, by status id.
+/// Batches are applied in order of arrival, so deletions of unknown statuses refer to statuses
+/// which were never scored or have been forgotten & are ignored
+pub struct RecentSamples {
+    capacity: usize,
+    index: Mutex<Index>,
+}
+
+impl RecentSamples {
+    pub fn new() -> Self {
+        Self::with_capacity(RECENT_STATUSES)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            index: Mutex::new(Index {
+                samples: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Remember the sample of a scored status, forgetting the oldest samples beyond the capacity.
+    /// Ids of deleted statuses are left in the scoring order & skipped once they are forgotten
+    pub fn insert(&self, id: u64, sample: Sample) {
+        let mut index = self.index.lock().expect("Recent samples lock was poisoned");
+        if index.samples.insert(id, sample).is_none() {
+            index.order.push_back(id);
+        }
+        while index.order.len() > self.capacity {
+            if let Some(oldest) = index.order.pop_front() {
+                index.samples.remove(&oldest);
+            }
+        }
+    }
+
+    /// Take the sample of a deleted status, if it is one of the recently scored statuses
+    pub fn take(&self, id: u64) -> Option<Sample> {
+        self.index
+            .lock()
+            .expect("Recent samples lock was poisoned")
+            .samples
+            .remove(&id)
+    }
+}
+
+impl Default for RecentSamples {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forget_oldest_samples() {
+        let recent = RecentSamples::with_capacity(2);
+        for id in 1..=3 {
+            recent.insert(
+                id,
+                Sample {
+                    time: id as i64,
+                    value: 1,
+                    weight: 1,
+                },
+            );
+        }
+        assert_eq!(recent.take(1), None, "Oldest sample should be forgotten");
+        assert_eq!(
+            recent.take(3),
+            Some(Sample {
+                time: 3,
+                value: 1,
+                weight: 1
+            })
+        );
+        assert_eq!(recent.take(3), None);
+    }
+
+    #[test]
+    fn ignore_unknown_deletions() {
+        let recent = RecentSamples::with_capacity(2);
+        recent.insert(
+            1,
+            Sample {
+                time: 1,
+                value: 1,
+                weight: 1,
+            },
+        );
+        recent.insert(
+            2,
+            Sample {
+                time: 2,
+                value: 1,
+                weight: 1,
+            },
+        );
+        for id in 3..10 {
+            assert_eq!(recent.take(id), None);
+        }
+        assert_eq!(
+            recent.take(1),
+            Some(Sample {
+                time: 1,
+                value: 1,
+                weight: 1
+            }),
+            "Unknown deletions should not evict samples"
+        );
+    }
+}
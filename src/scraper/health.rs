@@ -0,0 +1,172 @@
+use chrono::Utc;
+use metrics_runtime::Sink;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Name of every state, as recorded in the `topic_status` gauge
+const STATES: [&str; 5] = ["running", "backing_off", "failed", "stopped", "retained"];
+
+/// Health of the stream feeding a topic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum StreamStatus {
+    /// Stream is connected or connecting
+    Running,
+    /// Stream failed & is re-established at `retry_at` (epoch time)
+    BackingOff { reason: String, retry_at: i64 },
+    /// Stream failed permanently, no more tweets are received
+    Failed { reason: String },
+    /// Stream has ended, e.g. once a replay has finished
+    Stopped,
+    /// Topic is no longer subscribed to, only its time series is retained
+    Retained,
+}
+
+impl StreamStatus {
+    pub fn backing_off(reason: String, delay: Duration) -> Self {
+        StreamStatus::BackingOff {
+            reason,
+            retry_at: Utc::now().timestamp() + delay.as_secs() as i64,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamStatus::Running => STATES[0],
+            StreamStatus::BackingOff { .. } => STATES[1],
+            StreamStatus::Failed { .. } => STATES[2],
+            StreamStatus::Stopped => STATES[3],
+            StreamStatus::Retained => STATES[4],
+        }
+    }
+}
+
+/// Status of a topic, as reported by the `/topics` endpoint
+#[derive(Debug, Serialize)]
+pub struct TopicStatus {
+    pub topic: String,
+    #[serde(flatten)]
+    pub status: StreamStatus,
+}
+
+/// Record the `topic_status` state set of a topic: the gauge of its current state is 1, all others are 0.
+/// All gauges are 0 for topics which are no longer tracked
+pub fn record_status(sink: &mut Sink, topic: &str, status: Option<&StreamStatus>) {
+    let current = status.map(StreamStatus::name);
+    for state in STATES.iter() {
+        sink.record_gauge_with_labels(
+            "topic_status",
+            (current == Some(*state)) as i64,
+            &[("topic", topic.to_owned()), ("state", (*state).to_owned())],
+        );
+    }
+}
+
+struct Inner {
+    status: StreamStatus,
+    /// Topics fed by the stream, no longer updated once the stream is detached
+    topics: Vec<String>,
+    sink: Sink,
+}
+
+/// Status of a stream, shared between the stream & the scraper
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Health {
+    pub fn new(topics: Vec<String>, sink: Sink) -> Self {
+        let health = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                status: StreamStatus::Stopped,
+                topics,
+                sink,
+            })),
+        };
+        health.set(StreamStatus::Running);
+        health
+    }
+
+    pub fn status(&self) -> StreamStatus {
+        self.inner
+            .lock()
+            .expect("Health lock was poisoned")
+            .status
+            .clone()
+    }
+
+    /// Update the status, recording it for every topic of the stream
+    pub fn set(&self, status: StreamStatus) {
+        let mut inner = self.inner.lock().expect("Health lock was poisoned");
+        if inner.status == status {
+            return;
+        }
+        let Inner { topics, sink, .. } = &mut *inner;
+        for topic in topics.iter() {
+            record_status(sink, topic, Some(&status));
+        }
+        inner.status = status;
+    }
+
+    /// Mark a running stream as stopped once it has ended, keeping the reason of a failure
+    pub fn stop(&self) {
+        if self.status() == StreamStatus::Running {
+            self.set(StreamStatus::Stopped);
+        }
+    }
+
+    /// Replace the topics fed by the stream, recording the current status of the added topics
+    pub fn track(&self, topics: Vec<String>) {
+        let mut inner = self.inner.lock().expect("Health lock was poisoned");
+        let Inner {
+            status,
+            topics: tracked,
+            sink,
+        } = &mut *inner;
+        for topic in topics.iter().filter(|topic| !tracked.contains(topic)) {
+            record_status(sink, topic, Some(status));
+        }
+        *tracked = topics;
+    }
+
+    /// Stop recording the status for the topics of the stream, as they are fed by another stream
+    pub fn detach(&self) {
+        self.inner
+            .lock()
+            .expect("Health lock was poisoned")
+            .topics
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metrics_runtime::Receiver;
+
+    #[test]
+    fn keep_failure_reason() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let health = Health::new(vec!["rust".to_owned()], receiver.get_sink());
+        assert_eq!(health.status(), StreamStatus::Running);
+
+        let failed = StreamStatus::Failed {
+            reason: "Received HTTP error 401 Unauthorized".to_owned(),
+        };
+        health.set(failed.clone());
+        health.stop();
+        assert_eq!(
+            health.status(),
+            failed,
+            "A failed stream is not merely stopped"
+        );
+
+        let health = Health::new(vec!["rust".to_owned()], receiver.get_sink());
+        health.stop();
+        assert_eq!(health.status(), StreamStatus::Stopped);
+    }
+}
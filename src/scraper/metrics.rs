@@ -1,28 +1,980 @@
+use crate::config::RetentionConfig;
 use crate::scraper::sentiment;
-use serde_derive::Serialize;
-use std::sync::RwLock;
+use crate::scraper::storage::{Result, Storage, StorageError};
+use chrono::Utc;
+use log::error;
+use metrics_runtime::data::Counter;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 const DEFAULT_TIMESERIES_SIZE: usize = 10000;
+const DEFAULT_MAX_SAMPLES: usize = 1_000_000;
+/// Raw samples are served for windows up to 6 hours
+const RAW_WINDOW: i64 = 6 * 3600;
+/// Minute rollups are served for windows up to 7 days
+const MINUTE_WINDOW: i64 = 7 * 24 * 3600;
+/// Number of minute rollups kept, i.e. 7 days
+const MINUTE_ROLLUPS: usize = 7 * 24 * 60;
+/// Number of hourly rollups kept, i.e. 1 year
+const HOUR_ROLLUPS: usize = 365 * 24;
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize)]
 /// Time series sample for sentiment tracking
 pub struct Sample {
     /// Epoch time
     pub time: i64,
     /// Sentiment score
     pub value: sentiment::Value,
+    /// Number of samples this sample counts as in aggregations, e.g. to weight popular statuses
+    pub weight: u32,
+}
+
+/// Granularity of the stored time series data
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    /// Individual samples, at 1s precision
+    Raw,
+    /// 1 minute rollups
+    Minute,
+    /// 1 hour rollups
+    Hour,
+}
+
+impl Resolution {
+    /// Coarsest resolution which still gives a detailed view of the requested window,
+    /// which started `age` seconds ago. Windows older than the minute rollups are served hourly
+    pub fn for_window(window: i64, age: i64) -> Self {
+        if window <= RAW_WINDOW {
+            Resolution::Raw
+        } else if window <= MINUTE_WINDOW
+            && age <= Resolution::Minute.seconds() * MINUTE_ROLLUPS as i64
+        {
+            Resolution::Minute
+        } else {
+            Resolution::Hour
+        }
+    }
+
+    /// Bucket size in seconds
+    pub fn seconds(self) -> i64 {
+        match self {
+            Resolution::Raw => 1,
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
+/// Aggregate of all samples within a time bucket
+pub struct Rollup {
+    /// Epoch time at the start of the bucket
+    pub time: i64,
+    pub count: u64,
+    pub sum: i64,
+    pub min: sentiment::Value,
+    pub max: sentiment::Value,
+    pub mean: f64,
+}
+
+impl Rollup {
+    fn new(time: i64) -> Self {
+        Self {
+            time,
+            count: 0,
+            sum: 0,
+            min: sentiment::Value::MAX,
+            max: sentiment::Value::MIN,
+            mean: 0.0,
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        self.count += u64::from(sample.weight);
+        self.sum += sample.value * i64::from(sample.weight);
+        self.min = self.min.min(sample.value);
+        self.max = self.max.max(sample.value);
+        self.mean = self.sum as f64 / self.count as f64;
+    }
+
+    /// Remove a previously added sample.
+    /// When its value was the minimum or maximum, the bounds are recomputed from the `remaining` values
+    /// of the bucket if available, otherwise they are kept as is
+    fn remove(&mut self, sample: &Sample, remaining: Option<&[sentiment::Value]>) {
+        let value = sample.value;
+        self.count -= u64::from(sample.weight);
+        self.sum -= value * i64::from(sample.weight);
+        self.mean = if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        };
+        if let Some(remaining) = remaining.filter(|_| value == self.min || value == self.max) {
+            self.min = remaining
+                .iter()
+                .copied()
+                .min()
+                .unwrap_or(sentiment::Value::MAX);
+            self.max = remaining
+                .iter()
+                .copied()
+                .max()
+                .unwrap_or(sentiment::Value::MIN);
+        }
+    }
+}
+
+/// Bounded series of rollups at a fixed resolution
+struct RollupTier {
+    resolution: Resolution,
+    capacity: usize,
+    data: RwLock<VecDeque<Rollup>>,
+    /// Whether the rollups changed since they were last persisted
+    dirty: AtomicBool,
+}
+
+impl RollupTier {
+    fn new(resolution: Resolution, capacity: usize) -> Self {
+        Self {
+            resolution,
+            capacity,
+            data: RwLock::new(VecDeque::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Start from persisted rollups, keeping those of buckets before `until` only.
+    /// Later buckets are rebuilt from the restored raw samples
+    fn restore(&self, rollups: Vec<Rollup>, until: Option<i64>) -> Result<()> {
+        let mut store = self
+            .data
+            .write()
+            .map_err(|_| StorageError::Poisoned("Rollup"))?;
+        let until = until.map(|time| time - time.rem_euclid(self.resolution.seconds()));
+        store.clear();
+        store.extend(
+            rollups
+                .into_iter()
+                .filter(|rollup| until.map_or(true, |until| rollup.time < until)),
+        );
+        store.make_contiguous().sort_by_key(|rollup| rollup.time);
+        let overflow = store.len().saturating_sub(self.capacity);
+        store.drain(..overflow);
+        Ok(())
+    }
+
+    /// Persist the rollups if they changed since they were last persisted
+    fn persist(&self, topic: &str, storage: &dyn Storage) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let rollups = self
+            .data
+            .read()
+            .map_err(|_| StorageError::Poisoned("Rollup"))?
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        storage
+            .store_rollups(topic, self.resolution, &rollups)
+            .inspect_err(|_| self.dirty.store(true, Ordering::Release))
+    }
+
+    fn add(&self, samples: &[Sample]) -> Result<()> {
+        let mut store = self
+            .data
+            .write()
+            .map_err(|_| StorageError::Poisoned("Rollup"))?;
+        for sample in samples {
+            let bucket = sample.time - sample.time.rem_euclid(self.resolution.seconds());
+            // Samples mostly arrive in order, so check the latest bucket first
+            let index = match store.back() {
+                Some(last) if last.time == bucket => store.len() - 1,
+                Some(last) if last.time > bucket => {
+                    match store.binary_search_by_key(&bucket, |rollup| rollup.time) {
+                        Ok(index) => index,
+                        Err(index) => {
+                            store.insert(index, Rollup::new(bucket));
+                            index
+                        }
+                    }
+                }
+                _ => {
+                    store.push_back(Rollup::new(bucket));
+                    store.len() - 1
+                }
+            };
+            store[index].add(sample);
+        }
+        let overflow = store.len().saturating_sub(self.capacity);
+        store.drain(..overflow);
+        if !samples.is_empty() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Remove a retracted sample from its rollup.
+    /// `raw` holds the remaining raw samples, used to recompute the bounds of the rollup
+    fn remove(&self, sample: &Sample, raw: &VecDeque<Sample>) -> Result<()> {
+        let mut store = self
+            .data
+            .write()
+            .map_err(|_| StorageError::Poisoned("Rollup"))?;
+        let bucket = sample.time - sample.time.rem_euclid(self.resolution.seconds());
+        let index = match store.binary_search_by_key(&bucket, |rollup| rollup.time) {
+            Ok(index) => index,
+            // The rollup has already been evicted
+            Err(_) => return Ok(()),
+        };
+        self.dirty.store(true, Ordering::Release);
+        if store[index].count <= u64::from(sample.weight) {
+            store.remove(index);
+            return Ok(());
+        }
+        // Bounds can only be recomputed when the raw samples still cover the entire bucket
+        let remaining = raw.front().filter(|oldest| oldest.time <= bucket).map(|_| {
+            raw.iter()
+                .filter(|raw| raw.time >= bucket && raw.time < bucket + self.resolution.seconds())
+                .map(|raw| raw.value)
+                .collect::<Vec<_>>()
+        });
+        store[index].remove(sample, remaining.as_deref());
+        Ok(())
+    }
+
+    /// Whether rollups starting at `from` are still kept, given that at most `capacity` rollups are
+    fn retains(&self, from: i64, now: i64) -> bool {
+        now.saturating_sub(from) <= self.resolution.seconds() * self.capacity as i64
+    }
+
+    fn range(&self, from: i64, to: i64) -> Vec<Rollup> {
+        self.data
+            .read()
+            .map(|store| {
+                store
+                    .iter()
+                    .filter(|rollup| rollup.time >= from && rollup.time < to)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Time series data at a specific resolution
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Points {
+    Raw(Vec<Sample>),
+    Rollups(Vec<Rollup>),
+}
+
+/// Aggregation function applied to the samples within a bucket
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Mean,
+    Median,
+    Sum,
+    Count,
+    P90,
+}
+
+impl Aggregation {
+    /// Whether the aggregate can be derived from rollups instead of raw samples
+    fn derivable_from_rollups(self) -> bool {
+        match self {
+            Aggregation::Mean | Aggregation::Sum | Aggregation::Count => true,
+            Aggregation::Median | Aggregation::P90 => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
+/// Aggregated value of a time bucket
+pub struct Bucket {
+    /// Epoch time at the start of the bucket
+    pub time: i64,
+    pub value: f64,
+}
+
+/// Value at the 1-based `rank` of sorted `(value, weight)` pairs,
+/// where each value occurs as many times as its weight
+fn ranked(sorted: &[(sentiment::Value, u32)], rank: u64) -> sentiment::Value {
+    let mut cumulative = 0;
+    for &(value, weight) in sorted {
+        cumulative += u64::from(weight);
+        if cumulative >= rank {
+            return value;
+        }
+    }
+    sorted.last().map_or(0, |&(value, _)| value)
+}
+
+/// Nearest-rank percentile of sorted `(value, weight)` pairs with a total weight of `count`
+fn percentile(sorted: &[(sentiment::Value, u32)], count: u64, percentile: f64) -> f64 {
+    let rank = (percentile * count as f64).ceil() as u64;
+    ranked(sorted, rank.clamp(1, count)) as f64
+}
+
+fn median(sorted: &[(sentiment::Value, u32)], count: u64) -> f64 {
+    let middle = count / 2;
+    if count % 2 == 0 {
+        (ranked(sorted, middle) + ranked(sorted, middle + 1)) as f64 / 2.0
+    } else {
+        ranked(sorted, middle + 1) as f64
+    }
 }
 
 pub struct TimeSeries {
     pub topic: String,
-    pub data: RwLock<Vec<Sample>>,
+    pub data: RwLock<VecDeque<Sample>>,
+    minutes: RollupTier,
+    hours: RollupTier,
+    max_samples: usize,
+    max_age: Option<i64>,
+    storage: Arc<dyn Storage>,
+    /// Whether the samples are still persisted, held during every storage operation
+    /// so none is applied once the persisted samples have been discarded
+    persisted: Mutex<bool>,
+    evicted: Counter,
 }
 
 impl TimeSeries {
-    pub fn new(topic: &str) -> Self {
-        Self {
+    /// Create a time series, restoring previously persisted samples & rollups from storage.
+    /// Rollups are rebuilt from the restored samples from the bucket of the oldest sample onwards,
+    /// so the part of that bucket which was evicted before the restart is not accounted for
+    pub fn new(
+        topic: &str,
+        retention: RetentionConfig,
+        storage: Arc<dyn Storage>,
+        evicted: Counter,
+    ) -> Self {
+        let mut restored = storage.load(topic).unwrap_or_else(|err| {
+            error!(
+                "[{topic}] Could not restore time series: {err}",
+                topic = topic,
+                err = err
+            );
+            Vec::new()
+        });
+        // Samples are persisted in arrival order
+        restored.sort_by_key(|sample| sample.time);
+        let max_samples = retention.max_samples.unwrap_or(DEFAULT_MAX_SAMPLES);
+        let minutes = RollupTier::new(Resolution::Minute, MINUTE_ROLLUPS);
+        let hours = RollupTier::new(Resolution::Hour, HOUR_ROLLUPS);
+        let oldest = restored.first().map(|sample| sample.time);
+        let restore = |tier: &RollupTier| {
+            let rollups = storage.load_rollups(topic, tier.resolution)?;
+            tier.restore(rollups, oldest)?;
+            tier.add(&restored)
+        };
+        if let Err(err) = restore(&minutes).and_then(|_| restore(&hours)) {
+            error!(
+                "[{topic}] Could not restore rollups: {err}",
+                topic = topic,
+                err = err
+            );
+        }
+        let mut data = VecDeque::with_capacity(max_samples.min(DEFAULT_TIMESERIES_SIZE));
+        data.extend(restored);
+        let time_series = Self {
             topic: topic.to_owned(),
-            data: RwLock::new(Vec::with_capacity(DEFAULT_TIMESERIES_SIZE)),
+            data: RwLock::new(data),
+            minutes,
+            hours,
+            max_samples,
+            max_age: retention.max_age,
+            storage,
+            persisted: Mutex::new(true),
+            evicted,
+        };
+        if let Err(err) = time_series.compact() {
+            error!(
+                "[{topic}] Could not compact time series: {err}",
+                topic = topic,
+                err = err
+            );
+        }
+        time_series
+    }
+
+    /// Persist & store additional samples, evicting the oldest samples beyond the retention limits.
+    /// Samples are kept in chronological order, regardless of their arrival order
+    pub fn append(&self, samples: &[Sample]) -> Result<()> {
+        {
+            let persisted = self.persisted()?;
+            if !*persisted {
+                return Ok(());
+            }
+            self.storage.append(self.topic.as_str(), samples)?;
+        }
+        self.minutes.add(samples)?;
+        self.hours.add(samples)?;
+        let mut store = self
+            .data
+            .write()
+            .map_err(|_| StorageError::Poisoned("Time series"))?;
+        for &sample in samples {
+            // Late tweets are inserted in chronological order
+            if store.back().map_or(true, |last| last.time <= sample.time) {
+                store.push_back(sample);
+            } else {
+                let index = store.partition_point(|stored| stored.time <= sample.time);
+                store.insert(index, sample);
+            }
+        }
+        self.evict(&mut store);
+        Ok(())
+    }
+
+    /// Remove a previously appended sample, e.g. when its tweet was deleted.
+    /// Returns whether the sample was still part of the time series
+    pub fn retract(&self, sample: &Sample) -> Result<bool> {
+        let mut store = self
+            .data
+            .write()
+            .map_err(|_| StorageError::Poisoned("Time series"))?;
+        let start = store.partition_point(|stored| stored.time < sample.time);
+        let index = match (start..store.len())
+            .take_while(|&index| store[index].time == sample.time)
+            .find(|&index| store[index] == *sample)
+        {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let persisted = self.persisted()?;
+        if !*persisted {
+            return Ok(false);
+        }
+        self.storage
+            .retract(self.topic.as_str(), std::slice::from_ref(sample))?;
+        drop(persisted);
+        store.remove(index);
+        self.minutes.remove(sample, &store)?;
+        self.hours.remove(sample, &store)?;
+        Ok(true)
+    }
+
+    /// All data within the `[from, to)` epoch time range, at the requested resolution
+    pub fn range(&self, from: i64, to: i64, resolution: Resolution) -> Points {
+        match resolution {
+            Resolution::Raw => Points::Raw(self.samples(from, to)),
+            Resolution::Minute => Points::Rollups(self.minutes.range(from, to)),
+            Resolution::Hour => Points::Rollups(self.hours.range(from, to)),
+        }
+    }
+
+    /// Aggregate the data within the `[from, to)` epoch time range into buckets of `step` seconds.
+    /// An unbounded range has a `to` of `i64::MAX`. Empty buckets are omitted
+    pub fn aggregate(
+        &self,
+        from: i64,
+        to: i64,
+        step: i64,
+        aggregation: Aggregation,
+    ) -> Vec<Bucket> {
+        let bucket_of = |time: i64| from + (time - from) / step * step;
+
+        // Use the coarsest rollups which still align with the buckets & the range,
+        // as long as they have been kept since the start of the range
+        let now = Utc::now().timestamp();
+        let usable = |tier: &RollupTier| {
+            let seconds = tier.resolution.seconds();
+            step % seconds == 0
+                && from % seconds == 0
+                && (to % seconds == 0 || to == i64::MAX)
+                && tier.retains(from, now)
+        };
+        let tier = if !aggregation.derivable_from_rollups() {
+            None
+        } else if usable(&self.hours) {
+            Some(&self.hours)
+        } else if usable(&self.minutes) {
+            Some(&self.minutes)
+        } else {
+            None
+        };
+        if let Some(tier) = tier {
+            let mut buckets = BTreeMap::new();
+            for rollup in tier.range(from, to) {
+                let (count, sum) = buckets.entry(bucket_of(rollup.time)).or_insert((0, 0));
+                *count += rollup.count;
+                *sum += rollup.sum;
+            }
+            return buckets
+                .into_iter()
+                .map(|(time, (count, sum))| Bucket {
+                    time,
+                    value: match aggregation {
+                        Aggregation::Sum => sum as f64,
+                        Aggregation::Count => count as f64,
+                        _ => sum as f64 / count as f64,
+                    },
+                })
+                .collect();
+        }
+
+        // Weighted samples count as that many samples of the same value
+        let mut buckets = BTreeMap::new();
+        for sample in self.samples(from, to) {
+            buckets
+                .entry(bucket_of(sample.time))
+                .or_insert_with(Vec::new)
+                .push((sample.value, sample.weight));
+        }
+        buckets
+            .into_iter()
+            .filter_map(|(time, mut values)| {
+                values.sort_unstable();
+                let count = values
+                    .iter()
+                    .map(|&(_, weight)| u64::from(weight))
+                    .sum::<u64>();
+                // Samples without weight do not count
+                if count == 0 {
+                    return None;
+                }
+                let sum = values
+                    .iter()
+                    .map(|&(value, weight)| value * i64::from(weight))
+                    .sum::<sentiment::Value>() as f64;
+                let value = match aggregation {
+                    Aggregation::Mean => sum / count as f64,
+                    Aggregation::Median => median(&values, count),
+                    Aggregation::Sum => sum,
+                    Aggregation::Count => count as f64,
+                    Aggregation::P90 => percentile(&values, count, 0.9),
+                };
+                Some(Bucket { time, value })
+            })
+            .collect()
+    }
+
+    fn samples(&self, from: i64, to: i64) -> Vec<Sample> {
+        self.data
+            .read()
+            .map(|store| {
+                store
+                    .iter()
+                    .filter(|sample| sample.time >= from && sample.time < to)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Enforce the retention limits on both the in-memory & persisted samples
+    pub fn compact(&self) -> Result<()> {
+        let oldest = {
+            let mut store = self
+                .data
+                .write()
+                .map_err(|_| StorageError::Poisoned("Time series"))?;
+            self.evict(&mut store);
+            store.front().map(|sample| sample.time)
+        };
+        let persisted = self.persisted()?;
+        if !*persisted {
+            return Ok(());
+        }
+        // Rollups are persisted before the samples they were derived from are truncated
+        self.minutes.persist(&self.topic, self.storage.as_ref())?;
+        self.hours.persist(&self.topic, self.storage.as_ref())?;
+        match oldest {
+            Some(oldest) => self.storage.truncate(self.topic.as_str(), oldest),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the persisted samples of the time series.
+    /// Samples which are still appended afterwards, e.g. from tweets in flight, are no longer stored
+    pub fn discard(&self) -> Result<()> {
+        let mut persisted = self.persisted()?;
+        *persisted = false;
+        self.storage.remove(self.topic.as_str())
+    }
+
+    fn persisted(&self) -> Result<std::sync::MutexGuard<'_, bool>> {
+        self.persisted
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Time series"))
+    }
+
+    fn evict(&self, store: &mut VecDeque<Sample>) {
+        let mut evicted = store.len().saturating_sub(self.max_samples);
+        store.drain(..evicted);
+        if let Some(max_age) = self.max_age {
+            let min_time = Utc::now().timestamp() - max_age;
+            while store.front().is_some_and(|sample| sample.time < min_time) {
+                store.pop_front();
+                evicted += 1;
+            }
+        }
+        if evicted > 0 {
+            self.evicted.record(evicted as u64);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scraper::storage::{MemoryStorage, SegmentStorage};
+    use metrics_runtime::Receiver;
+
+    #[test]
+    fn evict_beyond_retention() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let retention = RetentionConfig {
+            max_samples: Some(3),
+            max_age: Some(3600),
+        };
+        let time_series = TimeSeries::new(
+            "music",
+            retention,
+            Arc::new(MemoryStorage),
+            receiver.get_sink().counter("samples_evicted"),
+        );
+
+        let now = Utc::now().timestamp();
+        let samples = [now - 7200, now - 3, now - 2, now - 1, now]
+            .iter()
+            .map(|&time| Sample {
+                time,
+                value: 1,
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+        time_series.append(&samples).expect("Could not append");
+
+        let store = time_series.data.read().expect("Could not read");
+        assert_eq!(
+            store.iter().map(|sample| sample.time).collect::<Vec<_>>(),
+            vec![now - 2, now - 1, now]
+        );
+    }
+
+    #[test]
+    fn insert_late_samples() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let time_series = TimeSeries::new(
+            "music",
+            RetentionConfig::default(),
+            Arc::new(MemoryStorage),
+            receiver.get_sink().counter("samples_evicted"),
+        );
+        for &time in &[10, 30, 20, 5, 30, 40] {
+            time_series
+                .append(&[Sample {
+                    time,
+                    value: 0,
+                    weight: 1,
+                }])
+                .expect("Could not append");
+        }
+
+        let store = time_series.data.read().expect("Could not read");
+        assert_eq!(
+            store.iter().map(|sample| sample.time).collect::<Vec<_>>(),
+            vec![5, 10, 20, 30, 30, 40]
+        );
+    }
+
+    #[test]
+    fn rollup_samples() {
+        let tier = RollupTier::new(Resolution::Minute, 2);
+        let samples = [(130, 4), (60, -2), (125, 2), (70, 1), (0, 7)]
+            .iter()
+            .map(|&(time, value)| Sample {
+                time,
+                value,
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+        tier.add(&samples).expect("Could not add samples");
+
+        let rollups = tier.range(i64::MIN, i64::MAX);
+        assert_eq!(rollups.len(), 2, "Oldest bucket should be evicted");
+        assert_eq!(
+            rollups[0],
+            Rollup {
+                time: 60,
+                count: 2,
+                sum: -1,
+                min: -2,
+                max: 1,
+                mean: -0.5
+            }
+        );
+        assert_eq!((rollups[1].time, rollups[1].count), (120, 2));
+        assert_eq!(rollups[1].mean, 3.0);
+    }
+
+    #[test]
+    fn retract_samples() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let time_series = TimeSeries::new(
+            "music",
+            RetentionConfig::default(),
+            Arc::new(MemoryStorage),
+            receiver.get_sink().counter("samples_evicted"),
+        );
+        let samples = [(0, 1), (10, 5), (20, 5), (30, -4), (70, 3)]
+            .iter()
+            .map(|&(time, value)| Sample {
+                time,
+                value,
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+        time_series.append(&samples).expect("Could not append");
+
+        let retract = |time, value| {
+            time_series
+                .retract(&Sample {
+                    time,
+                    value,
+                    weight: 1,
+                })
+                .expect("Could not retract")
+        };
+        assert!(retract(10, 5));
+        assert!(!retract(10, 5), "Samples are only retracted once");
+        assert!(!retract(20, 4), "Unknown samples are ignored");
+        assert!(retract(30, -4));
+        assert!(retract(70, 3));
+
+        let store = time_series.data.read().expect("Could not read");
+        assert_eq!(
+            store.iter().map(|sample| sample.time).collect::<Vec<_>>(),
+            vec![0, 20]
+        );
+        drop(store);
+        match time_series.range(0, 120, Resolution::Minute) {
+            Points::Rollups(rollups) => assert_eq!(
+                rollups,
+                vec![Rollup {
+                    time: 0,
+                    count: 2,
+                    sum: 6,
+                    min: 1,
+                    max: 5,
+                    mean: 3.0
+                }],
+                "Emptied rollups are removed & bounds recomputed"
+            ),
+            other => panic!("Expected rollups, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn aggregate_buckets() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let time_series = TimeSeries::new(
+            "music",
+            RetentionConfig::default(),
+            Arc::new(MemoryStorage),
+            receiver.get_sink().counter("samples_evicted"),
+        );
+        // Recent, hour aligned times, so the rollups are still retained
+        let base = Utc::now().timestamp() / 3600 * 3600 - 3600;
+        let samples = [
+            (0, 1),
+            (10, 5),
+            (20, 2),
+            (30, -4),
+            (65, 3),
+            (90, 9),
+            (130, 7),
+        ]
+        .iter()
+        .map(|&(time, value)| Sample {
+            time: base + time,
+            value,
+            weight: 1,
+        })
+        .collect::<Vec<_>>();
+        time_series.append(&samples).expect("Could not append");
+
+        let aggregate = |to, step, aggregation| {
+            time_series
+                .aggregate(base, base + to, step, aggregation)
+                .into_iter()
+                .map(|bucket| (bucket.time - base, bucket.value))
+                .collect::<Vec<_>>()
+        };
+        let values = |step, aggregation| aggregate(120, step, aggregation);
+        assert_eq!(
+            values(30, Aggregation::Count),
+            vec![(0, 3.0), (30, 1.0), (60, 1.0), (90, 1.0)]
+        );
+        assert_eq!(values(60, Aggregation::Median), vec![(0, 1.5), (60, 6.0)]);
+        assert_eq!(values(60, Aggregation::P90), vec![(0, 5.0), (60, 9.0)]);
+        assert_eq!(values(120, Aggregation::Sum), vec![(0, 16.0)]);
+        assert_eq!(values(60, Aggregation::Mean), vec![(0, 1.0), (60, 6.0)]);
+        assert_eq!(
+            aggregate(90, 60, Aggregation::Sum),
+            vec![(0, 4.0), (60, 3.0)],
+            "Rollups extending past the end of the range are not used"
+        );
+
+        // A weighted sample counts as that many samples
+        time_series
+            .append(&[
+                Sample {
+                    time: base + 150,
+                    value: 4,
+                    weight: 3,
+                },
+                Sample {
+                    time: base + 170,
+                    value: -2,
+                    weight: 1,
+                },
+            ])
+            .expect("Could not append");
+        for &(aggregation, value) in &[
+            (Aggregation::Mean, 3.4),
+            (Aggregation::Count, 5.0),
+            (Aggregation::Median, 4.0),
+            (Aggregation::P90, 7.0),
+        ] {
+            assert_eq!(
+                aggregate(180, 60, aggregation).last(),
+                Some(&(120, value)),
+                "Weighted {:?}",
+                aggregation
+            );
+        }
+    }
+
+    #[test]
+    fn discard_persisted_samples() {
+        let directory =
+            std::env::temp_dir().join(format!("sibyl-discard-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = Arc::new(SegmentStorage::open(&directory).expect("Could not open storage"));
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let time_series = TimeSeries::new(
+            "music",
+            RetentionConfig::default(),
+            storage.clone(),
+            receiver.get_sink().counter("samples_evicted"),
+        );
+        let sample = Sample {
+            time: 1,
+            value: 3,
+            weight: 1,
+        };
+        time_series.append(&[sample]).expect("Could not append");
+        time_series.discard().expect("Could not discard");
+        time_series
+            .append(&[Sample {
+                time: 2,
+                value: 1,
+                weight: 1,
+            }])
+            .expect("Could not append");
+        assert_eq!(time_series.retract(&sample).ok(), Some(false));
+        time_series.compact().expect("Could not compact");
+        assert!(
+            storage.load("music").expect("Could not load").is_empty(),
+            "Samples in flight are not persisted once discarded"
+        );
+        assert!(!directory.join("music").exists());
+    }
+
+    #[test]
+    fn restore_persisted_rollups() {
+        let directory =
+            std::env::temp_dir().join(format!("sibyl-rollup-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let open = || {
+            let storage =
+                Arc::new(SegmentStorage::open(&directory).expect("Could not open storage"));
+            TimeSeries::new(
+                "music",
+                RetentionConfig {
+                    max_samples: Some(2),
+                    max_age: None,
+                },
+                storage,
+                receiver.get_sink().counter("samples_evicted"),
+            )
+        };
+        let now = Utc::now().timestamp();
+        let base = now - now.rem_euclid(3600) - 3 * 3600;
+        let time_series = open();
+        let samples = [(0, 4), (60, -2), (3600, 1), (7210, 3), (7220, 5)]
+            .iter()
+            .map(|&(offset, value)| Sample {
+                time: base + offset,
+                value,
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+        time_series.append(&samples).expect("Could not append");
+        time_series.compact().expect("Could not compact");
+        let hours = time_series.hours.range(i64::MIN, i64::MAX);
+        let minutes = time_series.minutes.range(i64::MIN, i64::MAX);
+        assert_eq!(hours.len(), 3);
+        drop(time_series);
+
+        let time_series = open();
+        assert_eq!(
+            time_series.hours.range(i64::MIN, i64::MAX),
+            hours,
+            "Restored samples are not counted twice"
+        );
+        assert_eq!(time_series.minutes.range(i64::MIN, i64::MAX), minutes);
+        drop(time_series);
+
+        for entry in std::fs::read_dir(directory.join("music")).expect("Could not list segments") {
+            let path = entry.expect("Could not list segments").path();
+            if path.extension().is_some_and(|ext| ext == "seg") {
+                std::fs::remove_file(path).expect("Could not remove segment");
+            }
+        }
+        let time_series = open();
+        assert!(time_series.samples(i64::MIN, i64::MAX).is_empty());
+        assert_eq!(
+            time_series.hours.range(i64::MIN, i64::MAX),
+            hours,
+            "Rollups outlive the raw samples"
+        );
+        assert_eq!(time_series.minutes.range(i64::MIN, i64::MAX), minutes);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn select_resolution_by_age() {
+        let day = 24 * 3600;
+        assert_eq!(Resolution::for_window(3600, 30 * day), Resolution::Raw);
+        assert_eq!(Resolution::for_window(day, day), Resolution::Minute);
+        assert_eq!(
+            Resolution::for_window(day, 30 * day),
+            Resolution::Hour,
+            "Minute rollups are only kept for 7 days"
+        );
+        assert_eq!(Resolution::for_window(30 * day, 30 * day), Resolution::Hour);
+    }
+}
@@ -1,58 +1,361 @@
-use crate::config::ScraperConfig;
+use crate::config::{ScraperConfig, MAX_TOPICS};
+use crate::scraper::batches::batches;
+use crate::scraper::cancellable::cancellable;
+use crate::scraper::deletions::RecentSamples;
+use crate::scraper::health::{record_status, Health, StreamStatus, TopicStatus};
 use crate::scraper::metrics::{Sample, TimeSeries};
+use crate::scraper::recorder::Recorder;
+use crate::scraper::retweets::RetweetFilter;
+use crate::scraper::router::{Router, DEFAULT_SHED_THRESHOLD};
+use crate::scraper::source::{TweetSource, TwitterSource};
+use crate::scraper::storage::Storage;
 use crate::tweet::Tweet;
-//use chrono::NaiveDateTime;
 use chrono::Utc;
+use futures::future::Either;
 use futures::stream::Stream;
+use futures::sync::oneshot;
+use futures::Future;
 use log::{error, info, warn};
-use metrics_runtime::{Controller, Receiver};
-use std::cmp::max;
-use std::sync::Arc;
-use std::time::Instant;
+use metrics_runtime::{Controller, Receiver, Sink};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::runtime::{Runtime, TaskExecutor};
+use tokio_threadpool::{Sender, ThreadPool};
+use tokio_timer::{Delay, Interval};
 use twitter_stream::Token;
 
+mod backoff;
+mod batches;
+mod cancellable;
+mod deletions;
+pub mod health;
 pub mod metrics;
+mod queue;
 mod rate_controlled_stream;
+mod recorder;
+pub mod replay;
+mod retweets;
+mod router;
 mod sentiment;
+pub mod source;
+pub mod storage;
 
-//const TWITTER_DATE_FORMAT: &'static str = "%a %b %d %H:%M:%S %z %Y";
 const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Threads routing & queueing the tweets, independent of the number of topics as the scoring pool does the heavy lifting
+const INGEST_THREADS: usize = 2;
+/// Minimum interval between opening filter connections, so a burst of topic changes reconnects once
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reasons a topic (un)subscription can be refused
+#[derive(Debug)]
+pub enum TopicError {
+    AlreadySubscribed(String),
+    NotSubscribed(String),
+    /// The streaming API tracks a limited number of topics
+    TooMany,
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TopicError::AlreadySubscribed(topic) => {
+                write!(f, "Already subscribed to topic {}", topic)
+            }
+            TopicError::NotSubscribed(topic) => write!(f, "Not subscribed to topic {}", topic),
+            TopicError::TooMany => write!(f, "At most {} topics can be tracked", MAX_TOPICS),
+        }
+    }
+}
+
+/// Outcome of scoring a payload, applied to the time series in order of arrival
+enum Scored {
+    /// Sentiment sample, along with the id of its status
+    Sample(Option<u64>, Sample),
+    /// Deletion of a status
+    Delete(u64),
+}
+
+struct Topic {
+    time_series: Arc<TimeSeries>,
+    /// Cancels the compaction of the subscription when dropped, while its queued tweets are processed
+    /// until the router closes its queue. `None` for unsubscribed topics of which the time series was retained
+    subscription: Option<oneshot::Sender<()>>,
+}
+
+/// Shared filter connection, tracking all subscribed topics
+struct Connection {
+    topics: Vec<String>,
+    health: Health,
+    /// Closes the connection when dropped
+    _closer: oneshot::Sender<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The topics are either fed by the next connection or no longer tracked
+        self.health.detach();
+    }
+}
+
+/// Opens the shared filter connection, reopening it whenever the routed topics change
+struct Connector {
+    source: Arc<dyn TweetSource>,
+    router: Arc<Router>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    executor: TaskExecutor,
+    state: Mutex<ConnectorState>,
+}
+
+struct ConnectorState {
+    connection: Option<Connection>,
+    /// Time the last connection was opened
+    opened: Option<Instant>,
+    /// Cancels the scheduled reconnection when dropped
+    scheduled: Option<oneshot::Sender<()>>,
+    /// Set on shutdown, after which no more connections are opened
+    closed: bool,
+    sink: Sink,
+}
+
+impl Connector {
+    /// (Re)open the shared filter connection when the routed topics changed.
+    /// The API only permits a single filter connection per account, so it tracks all topics at once.
+    /// Connections are opened at most once every `RECONNECT_INTERVAL`, later changes are applied by a single
+    /// scheduled reconnection. Sources which do not filter by topic, e.g. a replay, keep their connection
+    fn connect(self: &Arc<Self>) {
+        let topics = self.router.topics();
+        let mut state = self.state.lock().expect("Connection lock was poisoned");
+        if state.closed {
+            return;
+        }
+        if let Some(connection) = state.connection.as_mut() {
+            if connection.topics == topics {
+                return;
+            }
+            if !self.source.filters_topics() {
+                info!("Routing tweets to topics {}", topics.join(", "));
+                connection.health.track(topics.clone());
+                connection.topics = topics;
+                return;
+            }
+        }
+        if topics.is_empty() {
+            // Dropping the previous connection closes it
+            state.connection = None;
+            info!("No topics left to track, closed filter connection");
+            return;
+        }
+        let now = Instant::now();
+        if let Some(reconnect_at) = state
+            .opened
+            .map(|opened| opened + RECONNECT_INTERVAL)
+            .filter(|&reconnect_at| reconnect_at > now)
+        {
+            if state.scheduled.is_none() {
+                info!(
+                    "Reconnecting in {}ms to track the changed topics",
+                    (reconnect_at - now).as_millis()
+                );
+                let (scheduled, cancelled) = oneshot::channel();
+                state.scheduled = Some(scheduled);
+                let connector = self.clone();
+                let reconnect = cancelled
+                    .select2(Delay::new(reconnect_at))
+                    .then(move |result| {
+                        match result {
+                            Ok(Either::B(_)) => {
+                                connector
+                                    .state
+                                    .lock()
+                                    .expect("Connection lock was poisoned")
+                                    .scheduled = None;
+                                connector.connect();
+                            }
+                            Err(Either::B((err, _))) => {
+                                error!("Reconnection timer failed: {}", err)
+                            }
+                            // The reconnection was cancelled
+                            _ => {}
+                        }
+                        Ok(())
+                    });
+                self.executor.spawn(reconnect);
+            }
+            return;
+        }
+        // Dropping the previous connection closes it
+        state.connection = None;
+        state.opened = Some(now);
+
+        info!("Tracking topics {}", topics.join(", "));
+        let (closer, close_signal) = oneshot::channel();
+        let health = Health::new(topics.clone(), state.sink.clone());
+        let failed = health.clone();
+        let ended = health.clone();
+        let router = self.router.clone();
+        let recorder = self.recorder.clone();
+        let raw_tweets = cancellable(
+            self.source.stream(&topics, health.clone()),
+            close_signal.shared(),
+        )
+        .inspect(move |item| {
+            if let Some(recorder) = &recorder {
+                let mut recorder = recorder.lock().expect("Recorder lock was poisoned");
+                if let Err(err) = recorder.record(item) {
+                    error!("Error recording raw payload: {}", err);
+                }
+            }
+        })
+        .map_err(move |err| {
+            error!("Error receiving tweets: {}", err);
+            failed.set(StreamStatus::Failed {
+                reason: err.to_string(),
+            });
+        })
+        .for_each(move |item| router.route(item))
+        .then(move |_| {
+            ended.stop();
+            Ok(())
+        });
+        self.executor.spawn(raw_tweets);
+        state.connection = Some(Connection {
+            topics,
+            health,
+            _closer: closer,
+        });
+    }
+
+    /// Close the connection & cancel any scheduled reconnection, no more connections are opened afterwards
+    fn close(&self) {
+        let mut state = self.state.lock().expect("Connection lock was poisoned");
+        state.closed = true;
+        state.scheduled = None;
+        state.connection = None;
+    }
+
+    /// Status of the current connection, if any
+    fn status(&self) -> Option<StreamStatus> {
+        self.state
+            .lock()
+            .expect("Connection lock was poisoned")
+            .connection
+            .as_ref()
+            .map(|connection| connection.health.status())
+    }
+}
 
 pub struct Scraper {
-    batch_size: usize,
-    api_token: Token<String, String>,
-    runtime: tokio::runtime::Runtime,
-    time_series: Vec<Arc<TimeSeries>>,
+    /// Maximum number of tweets processed at once, shared with all topic pipelines
+    batch_size: Arc<AtomicUsize>,
+    config: RwLock<ScraperConfig>,
+    storage: Arc<dyn Storage>,
+    executor: TaskExecutor,
+    runtime: Mutex<Option<Runtime>>,
+    /// Workers scoring the tweets of all topics
+    scoring: Sender,
+    scoring_pool: Mutex<Option<ThreadPool>>,
+    /// Number of scoring workers, which also bounds the batches in flight per topic
+    workers: usize,
+    topics: Mutex<Vec<Topic>>,
+    /// Classifies the tweets of the shared connection to their topics
+    router: Arc<Router>,
+    connector: Arc<Connector>,
     metrics: Receiver,
 }
 
 impl Scraper {
-    pub fn new(config: ScraperConfig) -> Self {
+    /// Create a scraper fetching tweets from the live Twitter API
+    pub fn new(config: ScraperConfig, storage: Arc<dyn Storage>) -> Self {
+        let api_token = Token::new(
+            config.consumer_key.expose().to_owned(),
+            config.consumer_secret.expose().to_owned(),
+            config.access_key.expose().to_owned(),
+            config.access_secret.expose().to_owned(),
+        );
+        let reconnect = config.reconnect.clone().unwrap_or_default();
+        Self::with_source(
+            config,
+            Arc::new(TwitterSource::new(api_token, reconnect)),
+            storage,
+        )
+    }
+
+    /// Create a scraper fetching tweets from the provided source
+    pub fn with_source(
+        config: ScraperConfig,
+        source: Arc<dyn TweetSource>,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
         let runtime = tokio::runtime::Builder::new()
-            .core_threads(max(config.topics.len() * 2, num_cpus::get()))
+            .core_threads(INGEST_THREADS)
+            .name_prefix("ingest-")
             .build()
             .expect("Could not initialize scraper runtime");
 
-        let api_token = Token::new(
-            config.consumer_key,
-            config.consumer_secret,
-            config.access_key,
-            config.access_secret,
-        );
+        let workers = config
+            .queue
+            .as_ref()
+            .and_then(|queue| queue.workers)
+            .unwrap_or_else(num_cpus::get);
+        let scoring_pool = tokio_threadpool::Builder::new()
+            .pool_size(workers)
+            .name_prefix("scoring-")
+            .build();
+
         let receiver = Receiver::builder()
             .build()
             .expect("failed to create metrics receiver");
-        let mut scraper = Self {
-            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
-            runtime,
-            api_token,
-            time_series: Vec::new(),
+        let mut sink = receiver.get_sink();
+        let router = Router::new(
+            sink.counter("tweets_unmatched"),
+            sink.counter("tweets_shed"),
+            config.shed_threshold.unwrap_or(DEFAULT_SHED_THRESHOLD),
+        );
+        let router = Arc::new(router);
+        let connector = Connector {
+            source,
+            router: router.clone(),
+            // Archives every raw payload of the shared connection
+            recorder: config
+                .record
+                .as_ref()
+                .map(|config| Arc::new(Mutex::new(Recorder::new("filter", config)))),
+            executor: runtime.executor(),
+            state: Mutex::new(ConnectorState {
+                connection: None,
+                opened: None,
+                scheduled: None,
+                closed: false,
+                sink: receiver.get_sink(),
+            }),
+        };
+        let scraper = Self {
+            batch_size: Arc::new(AtomicUsize::new(
+                config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            )),
+            executor: runtime.executor(),
+            runtime: Mutex::new(Some(runtime)),
+            scoring: scoring_pool.sender().clone(),
+            scoring_pool: Mutex::new(Some(scoring_pool)),
+            workers,
+            config: RwLock::new(config.clone()),
+            storage,
+            topics: Mutex::new(Vec::new()),
+            router,
+            connector: Arc::new(connector),
             metrics: receiver,
         };
-        config
-            .topics
-            .into_iter()
-            .for_each(|topic| scraper.subscribe_to(topic));
+        config.topics.into_iter().for_each(|topic| {
+            if let Err(err) = scraper.subscribe(topic) {
+                warn!("{}", err);
+            }
+        });
+        scraper.connect();
         scraper
     }
 
@@ -60,8 +363,47 @@ impl Scraper {
         self.metrics.get_controller()
     }
 
-    /// Subscribe to a stream of tweets containing the specified topic
-    pub fn subscribe_to(&mut self, topic: String) {
+    /// Sink to record additional metrics, exposed alongside the scraper metrics
+    pub fn metrics_sink(&self) -> Sink {
+        self.metrics.get_sink()
+    }
+
+    /// Subscribe to a stream of tweets containing the specified topic.
+    /// The time series of a previously retained topic is continued
+    pub fn subscribe_to(&self, topic: String) -> Result<(), TopicError> {
+        self.subscribe(topic)?;
+        self.connect();
+        Ok(())
+    }
+
+    /// (Re)open the shared filter connection when the subscribed topics changed
+    fn connect(&self) {
+        self.connector.connect();
+    }
+
+    /// Start processing the tweets of a topic, without updating the shared connection
+    fn subscribe(&self, topic: String) -> Result<(), TopicError> {
+        let (mut topics, existing, time_series) = loop {
+            let retained = self.is_retained(&topic)?;
+            // Restoring the stored samples can take a while, during which the topics remain available
+            let loaded = if retained {
+                None
+            } else {
+                Some(self.load(&topic))
+            };
+            let topics = self.topics.lock().expect("Topic lock was poisoned");
+            let existing = find_retained(&topics, &topic)?;
+            match (existing, loaded) {
+                (Some(index), _) => {
+                    let time_series = topics[index].time_series.clone();
+                    break (topics, existing, time_series);
+                }
+                (None, Some(loaded)) => break (topics, None, loaded),
+                // The retained time series has been removed in the meantime
+                (None, None) => continue,
+            }
+        };
+
         info!("Subscribing to topic {}", &topic);
         let mut sink = self.metrics.get_sink();
         let tweets_queued = sink.gauge_with_labels("tweets_queued", &[("topic", topic.clone())]);
@@ -71,115 +413,614 @@ impl Scraper {
         let processed_tweets =
             sink.counter_with_labels("tweets_processed", &[("topic", topic.clone())]);
         let storage_time = sink.histogram_with_labels("storage_time", &[("topic", topic.clone())]);
-        let batch_size = self.batch_size;
-        let executor = self.runtime.executor();
+        let skipped_retweets =
+            sink.counter_with_labels("retweets_skipped", &[("topic", topic.clone())]);
+        let config = self.config.read().expect("Config lock was poisoned");
+        let retweet_filter = Arc::new(RetweetFilter::new(config.retweets_for(topic.as_str())));
+        let retracted_samples =
+            sink.counter_with_labels("samples_retracted", &[("topic", topic.clone())]);
+        let recent_samples = Arc::new(RecentSamples::new());
+        let messages = sink.clone();
+        let topic_label = topic.clone();
+        let (subscription, cancel_signal) = oneshot::channel();
 
-        // Add a time series reference
-        let time_series = Arc::new(TimeSeries::new(topic.as_str()));
-        self.time_series.push(time_series.clone());
-
-        let tweet_analyzer = rate_controlled_stream::RateLimitedStream::from_topic(
-            self.api_token.clone(),
-            topic.clone(),
+        // Periodically enforce the retention policy, even when no new samples arrive
+        let compacted_series = time_series.clone();
+        let compaction = cancellable(
+            Interval::new_interval(COMPACTION_INTERVAL),
+            cancel_signal.shared(),
         )
-        .map_err(|err| error!("Error processing tweet batch: {}", err))
-        .chunks(2)
-        .map(move |items| {
-            // Clone all shared references
-            let processed_tweets = processed_tweets.clone();
-            let processing_time = processing_time.clone();
-            let stall_level = stall_level.clone();
-            let storage_time = storage_time.clone();
-            let time_series = time_series.clone();
-            let tweets_queued = tweets_queued.clone();
-
-            // Lazily schedule the batch processing onto the threadpool
-            let tweet_processing = futures::future::lazy(move || {
-                let samples = items.into_iter().filter_map(|item| {
-                    let start = Instant::now();
-                    serde_json::from_str::<Tweet>(&item)
-                        .map_err(|err| {
-                            error!(
-                                "Error while parsing tweet as JSON: {}\nTweet: {}",
-                                err, item
-                            )
-                        })
-                        .ok()
-                        .and_then(|tweet| match tweet {
+        .map_err(|err| error!("Compaction timer failed: {}", err))
+        .for_each(move |_| {
+            if let Err(err) = compacted_series.compact() {
+                error!("Error compacting time series: {}", err);
+            }
+            Ok(())
+        });
+        self.executor.spawn(compaction);
+
+        // Queue the routed tweets of the topic, which are scored by a pool of workers
+        let queue_config = config.queue.clone().unwrap_or_default();
+        drop(config);
+        let (queue, queued_tweets) = queue::bounded(
+            queue_config.capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY),
+            queue_config.overflow.unwrap_or_default(),
+            sink.gauge_with_labels("queue_depth", &[("topic", topic.clone())]),
+            sink.counter_with_labels("tweets_dropped", &[("topic", topic.clone())]),
+        );
+        let workers = self.workers;
+        self.router.add(topic.as_str(), queue);
+
+        let stream_series = time_series.clone();
+        let scoring = self.scoring.clone();
+        // The queue ends once the router drops its route & the queued tweets have been processed
+        let tweet_analyzer = batches(queued_tweets, self.batch_size.clone())
+            .map(move |items| {
+                // Clone all shared references
+                let processed_tweets = processed_tweets.clone();
+                let processing_time = processing_time.clone();
+                let stall_level = stall_level.clone();
+                let tweets_queued = tweets_queued.clone();
+                let retweet_filter = retweet_filter.clone();
+                let skipped_retweets = skipped_retweets.clone();
+                let mut messages = messages.clone();
+                let topic_label = topic_label.clone();
+
+                // Lazily score the batch on the scoring workers
+                let tweet_scoring = futures::future::lazy(move || {
+                    let batch_len = items.len();
+                    let mut scored = Vec::with_capacity(items.len());
+                    for item in items {
+                        let start = Instant::now();
+                        let tweet = match serde_json::from_str::<Tweet>(&item) {
+                            Ok(tweet) => tweet,
+                            Err(err) => {
+                                error!(
+                                    "Error while parsing tweet as JSON: {}\nTweet: {}",
+                                    err, item
+                                );
+                                continue;
+                            }
+                        };
+                        messages.record_counter_with_labels(
+                            "stream_messages",
+                            1,
+                            &[
+                                ("topic", topic_label.clone()),
+                                ("kind", tweet.kind().to_owned()),
+                            ],
+                        );
+                        match tweet {
                             Tweet::ApiLimit(limit) => {
                                 tweets_queued.record(limit.limit.track as i64);
-                                None
                             }
-                            Tweet::Content(content) => {
-                                Some(sentiment::message_value(content.text.as_str()))
+                            Tweet::Content(content) => match retweet_filter.weight(&content) {
+                                Some(weight) => scored.push(Scored::Sample(
+                                    content.id,
+                                    Sample {
+                                        // Fall back to the processing time for malformed dates
+                                        time: content
+                                            .time()
+                                            .unwrap_or_else(|| Utc::now().timestamp()),
+                                        value: sentiment::message_value(content.full_text()),
+                                        weight,
+                                    },
+                                )),
+                                None => skipped_retweets.record(1),
+                            },
+                            Tweet::Delete(delete) => {
+                                scored.push(Scored::Delete(delete.delete.status.id))
+                            }
+                            Tweet::ScrubGeo(_)
+                            | Tweet::StatusWithheld(_)
+                            | Tweet::UserWithheld(_) => {
+                                // Neither locations nor per-country availability affect the sentiment
                             }
                             Tweet::Disconnect(disconnect) => {
                                 warn!(
-                                    "[{topic}] Stream disconnected: {reason}",
-                                    topic = disconnect.stream_name,
-                                    reason = disconnect.reason
+                                    "[{topic}] Stream {stream} disconnected: {reason}",
+                                    topic = &topic_label,
+                                    stream = disconnect.disconnect.stream_name,
+                                    reason = disconnect.disconnect.reason
                                 );
-                                None
                             }
-                            Tweet::StallWarning(warning) => {
-                                stall_level.record(warning.percent_full as i64);
-                                None
-                            }
-                        })
-                        .map(|sample| {
-                            processing_time.record_timing(start, Instant::now());
-                            sample
-                        })
+                            Tweet::Warning(warning) => match warning.warning.percent_full {
+                                Some(percent_full) => stall_level.record(percent_full as i64),
+                                None => warn!(
+                                    "[{topic}] Stream warning {code}: {message}",
+                                    topic = &topic_label,
+                                    code = warning.warning.code,
+                                    message = warning.warning.message
+                                ),
+                            },
+                        }
+                        processing_time.record_timing(start, Instant::now());
+                    }
+                    processed_tweets.record(batch_len as u64);
+                    Ok(scored)
                 });
 
-                processed_tweets.record(batch_size as u64);
+                // Score the batch on the worker pool shared by all topics
+                oneshot::spawn(tweet_scoring, &scoring)
+            })
+            // Bound the number of batches in flight, the queue absorbs the remaining tweets.
+            // Scored batches are applied in the order of arrival, so deletions follow the tweets they delete
+            .buffered(workers)
+            .for_each(move |scored| {
+                // Samples of the batch, along with the id of their status
+                let mut samples: Vec<(Option<u64>, Sample)> = Vec::with_capacity(scored.len());
+                for item in scored {
+                    match item {
+                        Scored::Sample(id, sample) => {
+                            if let Some(id) = id {
+                                recent_samples.insert(id, sample);
+                            }
+                            samples.push((id, sample));
+                        }
+                        Scored::Delete(id) => {
+                            let sample = recent_samples.take(id);
+                            // The deleted status might be part of this batch, which is not stored yet
+                            let pending =
+                                samples.iter().position(|(status, _)| *status == Some(id));
+                            let retracted = match (pending, sample) {
+                                (Some(index), _) => {
+                                    samples.remove(index);
+                                    true
+                                }
+                                (None, Some(sample)) => {
+                                    stream_series.retract(&sample).unwrap_or_else(|err| {
+                                        error!("Error retracting sample: {}", err);
+                                        false
+                                    })
+                                }
+                                (None, None) => false,
+                            };
+                            if retracted {
+                                retracted_samples.record(1);
+                            }
+                        }
+                    }
+                }
+
                 let storage_start = Instant::now();
-                let timestamp = Utc::now().timestamp();
-                let result = time_series
-                    .data
-                    .write()
-                    .map(|mut store| {
-                        store.extend(samples.map(|value| Sample {
-                            time: timestamp,
-                            value,
-                        }));
-                    })
-                    .map_err(|err| error!("Error storing sample: {}", err));
+                let samples = samples
+                    .into_iter()
+                    .map(|(_, sample)| sample)
+                    .collect::<Vec<_>>();
+                if let Err(err) = stream_series.append(&samples) {
+                    error!("Error storing sample: {}", err);
+                }
                 storage_time.record_timing(storage_start, Instant::now());
-                result
+                Ok(())
             });
 
-            // Drive the stream indefinitely on the threadpool
-            Ok(executor.spawn(tweet_processing))
-        })
-        .buffer_unordered(100)
-        .for_each(|()| Ok(()));
+        self.executor.spawn(tweet_analyzer);
 
-        self.runtime.spawn(tweet_analyzer);
+        let entry = Topic {
+            time_series,
+            subscription: Some(subscription),
+        };
+        match existing {
+            Some(index) => topics[index] = entry,
+            None => topics.push(entry),
+        }
+        Ok(())
     }
 
+    /// Whether a time series of the topic has been retained, failing when the topic can't be subscribed to
+    fn is_retained(&self, topic: &str) -> Result<bool, TopicError> {
+        let topics = self.topics.lock().expect("Topic lock was poisoned");
+        find_retained(&topics, topic).map(|index| index.is_some())
+    }
+
+    /// Restore the time series of a topic from storage
+    fn load(&self, topic: &str) -> Arc<TimeSeries> {
+        let retention = self
+            .config
+            .read()
+            .expect("Config lock was poisoned")
+            .retention_for(topic);
+        let evicted = self
+            .metrics
+            .get_sink()
+            .counter_with_labels("samples_evicted", &[("topic", topic.to_owned())]);
+        Arc::new(TimeSeries::new(
+            topic,
+            retention,
+            self.storage.clone(),
+            evicted,
+        ))
+    }
+
+    /// Stop processing tweets for the specified topic.
+    /// The time series remains available when `retain` is set, otherwise its samples are discarded
+    pub fn unsubscribe(&self, topic: &str, retain: bool) -> Result<(), TopicError> {
+        self.remove_topic(topic, retain)?;
+        self.connect();
+        Ok(())
+    }
+
+    /// Stop processing the tweets of a topic, without updating the shared connection
+    fn remove_topic(&self, topic: &str, retain: bool) -> Result<(), TopicError> {
+        let mut topics = self.topics.lock().expect("Topic lock was poisoned");
+        let index = topics
+            .iter()
+            .position(|entry| entry.time_series.topic == topic)
+            .filter(|&index| !retain || topics[index].subscription.is_some())
+            .ok_or_else(|| TopicError::NotSubscribed(topic.to_owned()))?;
+
+        info!("Unsubscribing from topic {}", topic);
+        // Dropping the subscription cancels its compaction, while removing its route closes its queue
+        topics[index].subscription = None;
+        self.router.remove(topic);
+        let status = if retain {
+            Some(StreamStatus::Retained)
+        } else {
+            None
+        };
+        record_status(&mut self.metrics.get_sink(), topic, status.as_ref());
+        if !retain {
+            // Tweets still in flight are no longer stored once the time series is discarded
+            if let Err(err) = topics.remove(index).time_series.discard() {
+                error!(
+                    "[{topic}] Could not remove stored samples: {err}",
+                    topic = topic,
+                    err = err
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a reloaded configuration to the running scraper.
+    /// Topics added to the configuration are subscribed to, while removed topics are unsubscribed from,
+    /// retaining their time series. Topics managed at runtime are left untouched.
+    /// The batch size is applied immediately, other changes only affect topics subscribed from now on
+    pub fn reconfigure(&self, config: ScraperConfig) {
+        let previous = std::mem::replace(
+            &mut *self.config.write().expect("Config lock was poisoned"),
+            config.clone(),
+        );
+        if (
+            &previous.consumer_key,
+            &previous.consumer_secret,
+            &previous.access_key,
+            &previous.access_secret,
+        ) != (
+            &config.consumer_key,
+            &config.consumer_secret,
+            &config.access_key,
+            &config.access_secret,
+        ) {
+            warn!("Changed Twitter API credentials only take effect after a restart");
+        }
+        if previous.reconnect != config.reconnect {
+            warn!("Changed reconnection settings only take effect after a restart");
+        }
+        if previous.queue.as_ref().and_then(|queue| queue.workers)
+            != config.queue.as_ref().and_then(|queue| queue.workers)
+        {
+            warn!("Changed number of scoring workers only takes effect after a restart");
+        }
+
+        let batch_size = config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if self.batch_size.swap(batch_size, Ordering::Relaxed) != batch_size {
+            info!("Processing tweets in batches of at most {}", batch_size);
+        }
+
+        self.router
+            .set_shed_threshold(config.shed_threshold.unwrap_or(DEFAULT_SHED_THRESHOLD));
+
+        let previous_topics = previous.topics.iter().collect::<HashSet<_>>();
+        let topics = config.topics.iter().collect::<HashSet<_>>();
+        for topic in previous_topics.difference(&topics) {
+            if let Err(err) = self.remove_topic(topic, true) {
+                warn!("{}", err);
+            }
+        }
+        for topic in topics.difference(&previous_topics) {
+            if let Err(err) = self.subscribe((*topic).clone()) {
+                warn!("{}", err);
+            }
+        }
+        self.connect();
+    }
+
+    /// Close all topic streams & wait at most `timeout` for their in-flight tweets to be processed,
+    /// before flushing the storage
+    pub fn shutdown(&self, timeout: Duration) {
+        info!("Shutting down scraper");
+        self.connector.close();
+        // Removing the routes closes the topic queues, whose pipelines end once the queued tweets are processed
+        for entry in self
+            .topics
+            .lock()
+            .expect("Topic lock was poisoned")
+            .iter_mut()
+        {
+            self.router.remove(entry.time_series.topic.as_str());
+            entry.subscription = None;
+        }
+
+        let runtime = self
+            .runtime
+            .lock()
+            .expect("Runtime lock was poisoned")
+            .take();
+        if let Some(runtime) = runtime {
+            let (drained, is_drained) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = runtime.shutdown_on_idle().wait();
+                let _ = drained.send(());
+            });
+            if is_drained.recv_timeout(timeout).is_err() {
+                warn!(
+                    "Tweet processing did not finish within {}s, abandoning in-flight tweets",
+                    timeout.as_secs()
+                );
+            }
+        }
+        // Any batches still being scored are abandoned
+        drop(
+            self.scoring_pool
+                .lock()
+                .expect("Scoring pool lock was poisoned")
+                .take(),
+        );
+
+        // Complete the raw payload archive, a compressed archive is unreadable without its trailer
+        if let Some(recorder) = &self.connector.recorder {
+            let mut recorder = recorder.lock().expect("Recorder lock was poisoned");
+            if let Err(err) = recorder.rotate() {
+                error!("Could not close archive file: {}", err);
+            }
+        }
+        if let Err(err) = self.storage.flush() {
+            error!("Could not flush time series storage: {}", err);
+        }
+        info!("Scraper has stopped");
+    }
+
+    /// Status of all subscribed & retained topics
+    pub fn topic_status(&self) -> Vec<TopicStatus> {
+        let connection = self.connector.status();
+        self.topics
+            .lock()
+            .expect("Topic lock was poisoned")
+            .iter()
+            .map(|entry| TopicStatus {
+                topic: entry.time_series.topic.clone(),
+                status: match (&entry.subscription, &connection) {
+                    (None, _) => StreamStatus::Retained,
+                    (Some(_), Some(status)) => status.clone(),
+                    (Some(_), None) => StreamStatus::Stopped,
+                },
+            })
+            .collect()
+    }
+
+    /// Time series of all subscribed & retained topics
     pub fn time_series(&self) -> Vec<Arc<TimeSeries>> {
-        self.time_series.clone()
+        self.topics
+            .lock()
+            .expect("Topic lock was poisoned")
+            .iter()
+            .map(|entry| entry.time_series.clone())
+            .collect()
     }
 }
 
-//fn parse_time(time_str: &str) -> Result<NaiveDateTime, ()> {
-//    NaiveDateTime::parse_from_str(time_str, TWITTER_DATE_FORMAT)
-//        .map_err(|err| error!("Error parsing date: {}", err))
-//}
-
-//#[cfg(test)]
-//mod test {
-//    use super::*;
-//
-//    #[test]
-//    fn parse_twitter_date_string() {
-//        let sample_string = "Wed Oct 16 20:18:02 +0000 2019";
-//        let parsed = parse_time(sample_string);
-//        assert!(
-//            parsed.is_ok(),
-//            "Could not parse Twitter date string: {}",
-//            sample_string
-//        );
-//    }
-//}
+/// Position of the retained time series of a topic which is about to be subscribed to.
+/// Fails when the topic is already subscribed to, regardless of case as tracking is case insensitive,
+/// or when no more topics can be tracked
+fn find_retained(topics: &[Topic], topic: &str) -> Result<Option<usize>, TopicError> {
+    let topic_key = topic.to_lowercase();
+    let mut subscribed = topics.iter().filter(|entry| entry.subscription.is_some());
+    if subscribed
+        .clone()
+        .any(|entry| entry.time_series.topic.to_lowercase() == topic_key)
+    {
+        return Err(TopicError::AlreadySubscribed(topic.to_owned()));
+    }
+    if subscribed.nth(MAX_TOPICS - 1).is_some() {
+        return Err(TopicError::TooMany);
+    }
+    Ok(topics
+        .iter()
+        .position(|entry| entry.time_series.topic == topic))
+}
+
+/// Make a topic safe to use as part of a file name.
+/// Alphanumeric characters are kept, while every byte of other characters is encoded as `_` followed by its hex value,
+/// so distinct topics never share a file name
+pub(crate) fn sanitize_topic(topic: &str) -> String {
+    let mut sanitized = String::with_capacity(topic.len());
+    for c in topic.chars() {
+        if c.is_alphanumeric() {
+            sanitized.push(c);
+        } else {
+            let mut bytes = [0u8; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                sanitized.push_str(&format!("_{:02x}", byte));
+            }
+        }
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scraper::source::RawStream;
+    use crate::scraper::storage::MemoryStorage;
+    use futures::stream;
+    use metrics_runtime::Measurement;
+    use std::io::Read;
+
+    const TWEETS: usize = 20;
+
+    /// Delivers a fixed number of tweets about music
+    struct Tweets;
+
+    impl TweetSource for Tweets {
+        fn stream(&self, _topics: &[String], _health: Health) -> RawStream {
+            let tweet = r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"I love music"}"#;
+            Box::new(stream::iter_ok(vec![tweet.to_owned(); TWEETS]))
+        }
+    }
+
+    /// Counts the connections opened, which never deliver any tweets
+    struct Connections(Arc<AtomicUsize>);
+
+    impl TweetSource for Connections {
+        fn stream(&self, _topics: &[String], _health: Health) -> RawStream {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::new(stream::poll_fn(|| Ok(futures::Async::NotReady)))
+        }
+    }
+
+    fn queue_depth(scraper: &Scraper) -> Option<i64> {
+        scraper
+            .metrics()
+            .snapshot()
+            .into_measurements()
+            .into_iter()
+            .find(|(key, _)| key.name() == "queue_depth")
+            .and_then(|(_, measurement)| match measurement {
+                Measurement::Gauge(depth) => Some(depth),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn process_queued_tweets_on_shutdown() {
+        let config = toml::from_str::<ScraperConfig>(
+            "topics = [\"music\"]\nbatch_size = 1\n[queue]\nworkers = 1",
+        )
+        .expect("Invalid config");
+        let scraper = Scraper::with_source(config, Arc::new(Tweets), Arc::new(MemoryStorage));
+        // Occupy the only scoring worker, so the routed tweets remain queued
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        scraper
+            .scoring
+            .spawn(futures::future::lazy(move || {
+                let _ = released.recv();
+                Ok(())
+            }))
+            .expect("Could not block scoring worker");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while queue_depth(&scraper) != Some(TWEETS as i64 - 1) {
+            assert!(Instant::now() < deadline, "Tweets were not queued");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let _ = release.send(());
+        });
+        scraper.shutdown(Duration::from_secs(10));
+        let time_series = scraper.time_series();
+        assert_eq!(
+            time_series[0].data.read().expect("Could not read").len(),
+            TWEETS,
+            "Queued tweets are scored before shutting down"
+        );
+    }
+
+    #[test]
+    fn finish_recording_on_shutdown() {
+        let directory =
+            std::env::temp_dir().join(format!("sibyl-recording-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let config = toml::from_str::<ScraperConfig>(&format!(
+            "topics = [\"music\"]\n[record]\ndirectory = {:?}\ncompress = true",
+            directory.to_string_lossy()
+        ))
+        .expect("Invalid config");
+        let scraper = Scraper::with_source(config, Arc::new(Tweets), Arc::new(MemoryStorage));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let scored = || {
+            scraper.time_series()[0]
+                .data
+                .read()
+                .expect("Could not read")
+                .len()
+        };
+        while scored() < TWEETS {
+            assert!(Instant::now() < deadline, "Tweets were not processed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        scraper.shutdown(Duration::from_secs(10));
+
+        // The scraper is still alive, so the archive must have been completed by the shutdown
+        let mut archives = std::fs::read_dir(&directory)
+            .expect("Could not list archive directory")
+            .map(|entry| entry.expect("Invalid archive entry").path())
+            .collect::<Vec<_>>();
+        assert_eq!(archives.len(), 1);
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(
+            std::fs::File::open(archives.remove(0)).expect("Could not open archive"),
+        )
+        .read_to_string(&mut content)
+        .expect("Archive should be complete");
+        assert_eq!(content.lines().count(), TWEETS);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn reconnect_once_per_burst() {
+        let config =
+            toml::from_str::<ScraperConfig>("topics = [\"music\"]").expect("Invalid config");
+        let opened = Arc::new(AtomicUsize::new(0));
+        let scraper = Scraper::with_source(
+            config,
+            Arc::new(Connections(opened.clone())),
+            Arc::new(MemoryStorage),
+        );
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        scraper
+            .subscribe_to("food".to_owned())
+            .expect("Could not subscribe");
+        scraper
+            .subscribe_to("rust".to_owned())
+            .expect("Could not subscribe");
+        scraper
+            .unsubscribe("food", false)
+            .expect("Could not unsubscribe");
+        assert_eq!(
+            opened.load(Ordering::SeqCst),
+            1,
+            "Topic changes right after connecting are applied later"
+        );
+        let deadline = Instant::now() + RECONNECT_INTERVAL * 2;
+        while opened.load(Ordering::SeqCst) < 2 {
+            assert!(
+                Instant::now() < deadline,
+                "Changed topics were never tracked"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(
+            opened.load(Ordering::SeqCst),
+            2,
+            "A burst of topic changes reconnects once"
+        );
+        let state = scraper
+            .connector
+            .state
+            .lock()
+            .expect("Connection lock was poisoned");
+        assert_eq!(
+            state
+                .connection
+                .as_ref()
+                .map(|connection| connection.topics.clone()),
+            Some(vec!["music".to_owned(), "rust".to_owned()])
+        );
+        drop(state);
+        scraper.shutdown(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::OverflowPolicy;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use metrics_runtime::data::{Counter, Gauge};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+struct State {
+    /// Queued items, along with whether the overflow policy may discard them
+    items: VecDeque<(String, bool)>,
+    /// Number of queued items which may be discarded
+    droppable: usize,
+    /// Number of droppable items which arrived while the queue was full
+    overflowed: usize,
+    /// Set once every sender has been dropped
+    closed: bool,
+    /// Set once the receiver has been dropped, after which items are discarded
+    abandoned: bool,
+    consumer: Option<Task>,
+    /// Senders waiting for room in the queue
+    blocked: Vec<Task>,
+}
+
+impl State {
+    fn push(&mut self, item: String, droppable: bool) {
+        self.droppable += droppable as usize;
+        self.items.push_back((item, droppable));
+    }
+
+    fn remove(&mut self, index: usize) -> Option<String> {
+        let (item, droppable) = self.items.remove(index)?;
+        self.droppable -= droppable as usize;
+        Some(item)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Number of queued items
+    depth: Gauge,
+    /// Number of items discarded by the overflow policy
+    dropped: Counter,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Queue lock was poisoned")
+    }
+}
+
+/// Closes the queue once the last sender is dropped
+struct Closer(Arc<Shared>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.closed = true;
+        if let Some(consumer) = state.consumer.take() {
+            consumer.notify();
+        }
+    }
+}
+
+/// Sending half of a bounded queue
+#[derive(Clone)]
+pub struct QueueSender {
+    closer: Arc<Closer>,
+}
+
+/// Receiving half of a bounded queue, ending once all senders have been dropped & the queue is drained
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Bounded queue of raw payloads, applying the overflow policy once `capacity` items are queued.
+/// Only items sent as droppable are ever discarded, others are queued regardless of the capacity
+/// unless the policy blocks
+pub fn bounded(
+    capacity: usize,
+    policy: OverflowPolicy,
+    depth: Gauge,
+    dropped: Counter,
+) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            droppable: 0,
+            overflowed: 0,
+            closed: false,
+            abandoned: false,
+            consumer: None,
+            blocked: Vec::new(),
+        }),
+        capacity: capacity.max(1),
+        policy,
+        depth,
+        dropped,
+    });
+    (
+        QueueSender {
+            closer: Arc::new(Closer(shared.clone())),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl QueueSender {
+    /// Enqueue the item, completing once it has been queued or discarded.
+    /// Items which are not `droppable`, e.g. control messages, are never discarded
+    pub fn send(&self, item: String, droppable: bool) -> Enqueue {
+        Enqueue {
+            sender: self.clone(),
+            item: Some(item),
+            droppable,
+        }
+    }
+}
+
+/// Future of an item being enqueued
+pub struct Enqueue {
+    sender: QueueSender,
+    item: Option<String>,
+    droppable: bool,
+}
+
+impl Future for Enqueue {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let shared = &self.sender.closer.0;
+        let mut state = shared.lock();
+        if state.abandoned {
+            return Ok(Async::Ready(()));
+        }
+        let item = match self.item.take() {
+            Some(item) => item,
+            None => return Ok(Async::Ready(())),
+        };
+
+        let full = state.items.len() >= shared.capacity;
+        if full && shared.policy == OverflowPolicy::Block {
+            self.item = Some(item);
+            state.blocked.push(task::current());
+            return Ok(Async::NotReady);
+        }
+        if !full || !self.droppable {
+            if !full {
+                state.overflowed = 0;
+            }
+            state.push(item, self.droppable);
+        } else {
+            // The item or one of the queued droppable items is discarded
+            let victim = match shared.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.iter().position(|(_, droppable)| *droppable)
+                }
+                OverflowPolicy::Sample => {
+                    // Reservoir sampling: the n-th item arriving at the full queue replaces a random queued item
+                    // with a probability of capacity / n, so the queue remains a uniform sample of the arrived items
+                    state.overflowed += 1;
+                    let mut rng = rand::thread_rng();
+                    if rng.gen_range(0, shared.capacity + state.overflowed) < shared.capacity {
+                        let nth = rng.gen_range(0, state.droppable.max(1));
+                        state
+                            .items
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, (_, droppable))| *droppable)
+                            .nth(nth)
+                            .map(|(index, _)| index)
+                    } else {
+                        None
+                    }
+                }
+                OverflowPolicy::Block => unreachable!("Blocking senders never discard items"),
+            };
+            if let Some(index) = victim {
+                state.remove(index);
+                state.push(item, true);
+            }
+            shared.dropped.record(1);
+        }
+        shared.depth.record(state.items.len() as i64);
+        if let Some(consumer) = state.consumer.take() {
+            consumer.notify();
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Stream for QueueReceiver {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        let mut state = self.shared.lock();
+        match state.remove(0) {
+            Some(item) => {
+                self.shared.depth.record(state.items.len() as i64);
+                state.blocked.drain(..).for_each(|sender| sender.notify());
+                Ok(Async::Ready(Some(item)))
+            }
+            None if state.closed => Ok(Async::Ready(None)),
+            None => {
+                state.consumer = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.abandoned = true;
+        state.items.clear();
+        state.droppable = 0;
+        self.shared.depth.record(0);
+        state.blocked.drain(..).for_each(|sender| sender.notify());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use metrics_runtime::{Measurement, Receiver};
+
+    fn queue(policy: OverflowPolicy) -> (Receiver, QueueSender, QueueReceiver) {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let mut sink = receiver.get_sink();
+        let (sender, queue) = bounded(
+            2,
+            policy,
+            sink.gauge("queue_depth"),
+            sink.counter("tweets_dropped"),
+        );
+        (receiver, sender, queue)
+    }
+
+    fn fill(sender: &QueueSender, items: &[&str]) {
+        for item in items {
+            sender
+                .send((*item).to_owned(), !item.starts_with("control"))
+                .wait()
+                .expect("Could not queue item");
+        }
+    }
+
+    fn measurement(receiver: &Receiver, name: &str) -> Option<Measurement> {
+        receiver
+            .get_controller()
+            .snapshot()
+            .into_measurements()
+            .into_iter()
+            .find(|(key, _)| key.name() == name)
+            .map(|(_, measurement)| measurement)
+    }
+
+    #[test]
+    fn apply_overflow_policy() {
+        let (metrics, sender, receiver) = queue(OverflowPolicy::DropOldest);
+        fill(&sender, &["control 1", "2", "3", "4", "control 5"]);
+        assert!(matches!(
+            measurement(&metrics, "queue_depth"),
+            Some(Measurement::Gauge(3))
+        ));
+        assert!(matches!(
+            measurement(&metrics, "tweets_dropped"),
+            Some(Measurement::Counter(2))
+        ));
+        drop(sender);
+        assert_eq!(
+            receiver.collect().wait(),
+            Ok(vec![
+                "control 1".to_owned(),
+                "4".to_owned(),
+                "control 5".to_owned()
+            ]),
+            "Control messages are never dropped"
+        );
+
+        let (metrics, sender, receiver) = queue(OverflowPolicy::Sample);
+        fill(&sender, &["1", "2", "control 3", "4", "5"]);
+        assert!(matches!(
+            measurement(&metrics, "tweets_dropped"),
+            Some(Measurement::Counter(2))
+        ));
+        drop(sender);
+        let sampled = receiver.collect().wait().expect("Could not drain queue");
+        assert_eq!(sampled.len(), 3);
+        assert!(sampled.contains(&"control 3".to_owned()));
+
+        let (_metrics, sender, mut receiver) = queue(OverflowPolicy::Block);
+        fill(&sender, &["1", "2"]);
+        let mut blocked = sender.send("control 3".to_owned(), false);
+        future::lazy(|| {
+            assert_eq!(
+                blocked.poll(),
+                Ok(Async::NotReady),
+                "Full queue should block"
+            );
+            assert_eq!(receiver.poll(), Ok(Async::Ready(Some("1".to_owned()))));
+            assert_eq!(blocked.poll(), Ok(Async::Ready(())));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("Could not poll queue");
+        drop((sender, blocked));
+        assert_eq!(
+            receiver.collect().wait(),
+            Ok(vec!["2".to_owned(), "control 3".to_owned()])
+        );
+    }
+
+    #[test]
+    fn sample_uniformly() {
+        let metrics = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let mut sink = metrics.get_sink();
+        // Every item should remain queued with a probability of capacity / items
+        let mut kept = [0; 4];
+        for _ in 0..2_000 {
+            let (sender, receiver) = bounded(
+                2,
+                OverflowPolicy::Sample,
+                sink.gauge("queue_depth"),
+                sink.counter("tweets_dropped"),
+            );
+            fill(&sender, &["0", "1", "2", "3"]);
+            drop(sender);
+            for item in receiver.collect().wait().expect("Could not drain queue") {
+                kept[item.parse::<usize>().expect("Invalid item")] += 1;
+            }
+        }
+        for count in kept.iter() {
+            assert!((800..1_200).contains(count), "Biased sample: {:?}", kept);
+        }
+    }
+}
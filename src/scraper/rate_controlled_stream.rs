@@ -1,138 +1,547 @@
+use crate::config::ReconnectConfig;
+use crate::scraper::backoff::{Backoff, Failure};
+use crate::scraper::health::{Health, StreamStatus};
+use crate::scraper::source::StreamError;
 use futures::future::Future;
-use futures::{Async, FlattenStream, Stream};
+use futures::{stream, Async, Stream};
 use log::{error, info};
-use std::time::{Duration, Instant};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio_timer::Delay;
 use twitter_stream::error::Error as TwitterError;
-use twitter_stream::{
-    types::StatusCode, FutureTwitterStream, Token, TwitterStream, TwitterStreamBuilder,
-};
+use twitter_stream::{types::StatusCode, Token, TwitterStreamBuilder};
 
 type ApiToken = Token<String, String>;
 
+/// Raw payloads received over a filter connection
+type Connection = Box<dyn Stream<Item = String, Error = TwitterError> + Send>;
+/// Opens a new filter connection
+type Connect = Box<dyn Fn() -> Connection + Send>;
+
 pub struct RateLimitedStream {
-    inner: FlattenStream<FutureTwitterStream>,
-    topic: String,
-    api_token: ApiToken,
+    inner: Connection,
+    connect: Connect,
+    /// Comma separated list of all tracked topics
+    track: String,
     state: StreamAction,
-    previous_delay: u64,
+    /// Shared with the previous & next connections
+    backoff: Arc<Mutex<Backoff>>,
+    health: Health,
+    /// The client times out once no data, including keep-alives, was received for this long
+    idle_timeout: Duration,
+}
+
+/// Reconnect when no data was received for this long, as Twitter sends keep-alives every 30 seconds
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 90_000;
+
+fn filter_builder(
+    api_token: ApiToken,
+    track: &str,
+    idle_timeout: Duration,
+) -> TwitterStreamBuilder<'_, ApiToken> {
+    let mut builder = TwitterStreamBuilder::filter(api_token);
+    builder
+        .track(track)
+        // Stall warnings report the stream falling behind, so tweets are shed before the server disconnects
+        .stall_warnings(true)
+        // Times out once no data arrived for this long, keep-alives included, as they are consumed by the client
+        .timeout(Some(idle_timeout));
+    builder
 }
 
-fn create_stream(api_token: ApiToken, topic: &str) -> FlattenStream<FutureTwitterStream> {
-    TwitterStreamBuilder::filter(api_token)
-        .track(topic)
+fn create_stream(
+    api_token: ApiToken,
+    track: &str,
+    idle_timeout: Duration,
+) -> Result<Connection, TwitterError> {
+    let connection = filter_builder(api_token, track, idle_timeout)
         .listen()
-        .unwrap()
-        .flatten_stream()
+        .map_err(TwitterError::custom)?;
+    Ok(Box::new(
+        connection.flatten_stream().map(|item| item.to_string()),
+    ))
 }
 
 impl RateLimitedStream {
-    pub fn from_topic(api_token: ApiToken, topic: String) -> Self {
+    /// Open a single filter connection tracking all topics,
+    /// as the API only permits one concurrent filter connection per account.
+    /// The connection is only opened once the backoff of a previous connection has passed
+    pub fn from_topics(
+        api_token: ApiToken,
+        topics: &[String],
+        reconnect: &ReconnectConfig,
+        backoff: Arc<Mutex<Backoff>>,
+        health: Health,
+    ) -> Self {
+        let track = topics.join(",");
+        let idle_timeout =
+            Duration::from_millis(reconnect.idle_timeout_ms.unwrap_or(DEFAULT_IDLE_TIMEOUT_MS));
+        let connect = {
+            let track = track.clone();
+            // The connection cannot be set up without TLS, which the stream reports as a fatal error
+            Box::new(move || {
+                create_stream(api_token.clone(), track.as_str(), idle_timeout)
+                    .unwrap_or_else(|err| Box::new(stream::once(Err(err))))
+            })
+        };
+        Self::new(track, connect, reconnect, backoff, health)
+    }
+
+    fn new(
+        track: String,
+        connect: Connect,
+        reconnect: &ReconnectConfig,
+        backoff: Arc<Mutex<Backoff>>,
+        health: Health,
+    ) -> Self {
+        let idle_timeout =
+            Duration::from_millis(reconnect.idle_timeout_ms.unwrap_or(DEFAULT_IDLE_TIMEOUT_MS));
+        let pending = backoff.lock().expect("Backoff lock was poisoned").pending();
+        let (inner, state): (Connection, _) = match pending {
+            Some((delay, waiter)) => {
+                info!(
+                    "[{track}] Connecting after {delay}ms, as the previous connection is backing off",
+                    track = &track,
+                    delay = delay.as_millis()
+                );
+                health.set(StreamStatus::backing_off(
+                    "Previous connection is backing off".to_owned(),
+                    delay,
+                ));
+                (
+                    Box::new(stream::empty()),
+                    StreamAction::RestartAfter { waiter },
+                )
+            }
+            None => (connect(), StreamAction::Continue),
+        };
         RateLimitedStream {
-            inner: create_stream(api_token.clone(), topic.as_str()),
-            topic,
-            api_token,
-            state: StreamAction::Continue,
-            previous_delay: 0,
+            inner,
+            connect,
+            track,
+            state,
+            backoff,
+            health,
+            idle_timeout,
         }
     }
+
+    /// End the stream, as it cannot be restarted without a working timer
+    fn timer_failed(&mut self, context: &str, err: tokio_timer::Error) -> StreamError {
+        error!(
+            "[{track}] {context}: {err}",
+            track = &self.track,
+            context = context,
+            err = err
+        );
+        self.health.set(StreamStatus::Failed {
+            reason: format!("Stream timer failed: {}", err),
+        });
+        self.state = StreamAction::Exit;
+        StreamError::Timer(err)
+    }
+
+    /// Close the connection & schedule a reconnection attempt, backing off according to the failure
+    fn reconnect_after(&mut self, failure: Failure, reason: impl Display) {
+        let (delay, waiter) = self
+            .backoff
+            .lock()
+            .expect("Backoff lock was poisoned")
+            .wait(failure);
+        error!(
+            "[{track}] {reason}, reconnecting after {delay}ms",
+            track = &self.track,
+            reason = reason,
+            delay = delay.as_millis()
+        );
+        self.health
+            .set(StreamStatus::backing_off(reason.to_string(), delay));
+        self.state = StreamAction::RestartAfter { waiter };
+    }
 }
 
 #[derive(Debug)]
 enum StreamAction {
     Continue,
-    RestartAfter { seconds: u64, waiter: Delay },
+    RestartAfter {
+        waiter: Delay,
+    },
+    /// Terminal state after a fatal error, the stream has ended
     Exit,
 }
 
-/// Derive an action based on twitter error codes
+/// Classify twitter error codes, `None` when reconnecting will not resolve the error
 /// c.f.r. https://developer.twitter.com/en/docs/basics/response-codes
-fn process_twitter_error(status_code: StatusCode, previous_delay: u64) -> StreamAction {
+fn classify_status(status_code: StatusCode) -> Option<Failure> {
     match status_code.as_u16() {
-        410 => StreamAction::RestartAfter {
-            seconds: 0,
-            waiter: Delay::new(Instant::now()),
-        },
-        420 | 429 => StreamAction::RestartAfter {
-            seconds: previous_delay + 60,
-            waiter: Delay::new(
-                Instant::now()
-                    .checked_add(Duration::from_secs(previous_delay + 60))
-                    .unwrap(),
-            ),
-        },
-        304 => StreamAction::RestartAfter {
-            seconds: 300,
-            waiter: Delay::new(
-                Instant::now()
-                    .checked_add(Duration::from_secs(300))
-                    .unwrap(),
-            ),
-        },
-        400 | 401 | 403 | 404 | 406 | 422 => StreamAction::Exit,
-        _ => StreamAction::Continue,
+        420 | 429 => Some(Failure::RateLimited),
+        400 | 401 | 403 | 404 | 406 | 422 => None,
+        _ => Some(Failure::Http),
     }
 }
 
 impl Stream for RateLimitedStream {
-    type Item = <TwitterStream as Stream>::Item;
-    type Error = <TwitterStream as Stream>::Error;
+    type Item = String;
+    type Error = StreamError;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
         match &mut self.state {
             StreamAction::Continue => match self.inner.poll() {
                 Err(TwitterError::Http(status_code)) => {
-                    self.state = process_twitter_error(status_code, self.previous_delay);
-                    match &self.state {
-                        StreamAction::RestartAfter { seconds, .. } => {
+                    match classify_status(status_code) {
+                        Some(failure) => self.reconnect_after(
+                            failure,
+                            format_args!("Received HTTP error {}", status_code),
+                        ),
+                        None => {
+                            let reason = format!("Received HTTP error {}", status_code);
                             error!(
-                                "[{topic}] Reached twitter API limit, restarting stream after {delay}s delay",
-                                topic = &self.topic,
-                                delay = seconds
+                                "[{track}] {reason}, giving up",
+                                track = &self.track,
+                                reason = reason
                             );
-                            self.previous_delay = *seconds;
+                            self.health.set(StreamStatus::Failed { reason });
+                            // End the stream, as reconnecting will not resolve the error
+                            self.state = StreamAction::Exit;
+                            return Ok(Async::Ready(None));
                         }
-                        StreamAction::Continue => (),
-                        StreamAction::Exit => (),
                     }
                     futures::task::current().notify();
                     Ok(Async::NotReady)
                 }
+                Err(TwitterError::Custom(err)) => {
+                    let reason = format!("Could not connect: {}", err);
+                    error!(
+                        "[{track}] {reason}, giving up",
+                        track = &self.track,
+                        reason = reason
+                    );
+                    self.health.set(StreamStatus::Failed { reason });
+                    self.state = StreamAction::Exit;
+                    Ok(Async::Ready(None))
+                }
+                Err(TwitterError::TimedOut) => {
+                    let idle_timeout = self.idle_timeout;
+                    self.reconnect_after(
+                        Failure::Network,
+                        format_args!("No data received for {}ms", idle_timeout.as_millis()),
+                    );
+                    futures::task::current().notify();
+                    Ok(Async::NotReady)
+                }
                 Err(other_err) => {
-                    error!("Received API error {}", other_err);
-                    Err(other_err)
+                    self.reconnect_after(
+                        Failure::Network,
+                        format_args!("Received API error {}", other_err),
+                    );
+                    futures::task::current().notify();
+                    Ok(Async::NotReady)
                 }
                 Ok(Async::NotReady) => Ok(Async::NotReady),
                 Ok(Async::Ready(Some(content))) => {
-                    self.previous_delay = 0;
+                    self.backoff
+                        .lock()
+                        .expect("Backoff lock was poisoned")
+                        .reset();
                     Ok(Async::Ready(Some(content)))
                 }
                 Ok(Async::Ready(None)) => {
-                    error!("[{topic}] Stream has stopped", topic = &self.topic);
-                    Ok(Async::Ready(None))
+                    self.reconnect_after(Failure::Network, "Stream has stopped");
+                    futures::task::current().notify();
+                    Ok(Async::NotReady)
                 }
             },
-            StreamAction::RestartAfter { ref mut waiter, .. } => match waiter.poll() {
+            StreamAction::RestartAfter { waiter } => match waiter.poll() {
                 Ok(Async::Ready(())) => {
-                    info!("[{topic}] Restart stream", topic = &self.topic);
+                    info!("[{track}] Restart stream", track = &self.track);
+                    self.health.set(StreamStatus::Running);
                     self.state = StreamAction::Continue;
-                    self.inner = create_stream(self.api_token.clone(), self.topic.as_str());
+                    self.inner = (self.connect)();
                     futures::task::current().notify();
                     Ok(Async::NotReady)
                 }
                 Ok(Async::NotReady) => Ok(Async::NotReady),
-                Err(err) => {
-                    error!(
-                        "[{topic}] Could not wait for stream restart: {err}",
-                        topic = &self.topic,
-                        err = err
-                    );
-                    Err(TwitterError::TimedOut)
-                }
+                Err(err) => Err(self.timer_failed("Could not wait for stream restart", err)),
             },
-            StreamAction::Exit => {
-                panic!("[{topic}] Cancel twitter topic stream", topic = &self.topic);
-            }
+            StreamAction::Exit => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use metrics_runtime::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio_executor::park::ParkThread;
+    use tokio_timer::clock::{self, Clock, Now};
+    use tokio_timer::{timer, Timer};
+
+    /// Clock which only advances when told to
+    #[derive(Clone)]
+    struct MockNow(Arc<Mutex<Instant>>);
+
+    impl Now for MockNow {
+        fn now(&self) -> Instant {
+            *self.0.lock().expect("Clock lock was poisoned")
+        }
+    }
+
+    impl MockNow {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().expect("Clock lock was poisoned") += duration;
         }
     }
+
+    /// Connections failing with the errors in turn, after which a connection delivers a tweet & stays open
+    fn connections(errors: Vec<TwitterError>) -> Connect {
+        let errors = Mutex::new(errors.into_iter());
+        Box::new(
+            move || match errors.lock().expect("Errors lock was poisoned").next() {
+                Some(err) => Box::new(stream::once(Err(err))),
+                None => Box::new(
+                    stream::once(Ok("tweet".to_owned()))
+                        .chain(stream::poll_fn(|| Ok(Async::NotReady))),
+                ),
+            },
+        )
+    }
+
+    fn rate_limited(
+        connect: Connect,
+        reconnect: ReconnectConfig,
+        health: Health,
+    ) -> RateLimitedStream {
+        let backoff = Arc::new(Mutex::new(Backoff::new(&reconnect)));
+        RateLimitedStream::new("rust".to_owned(), connect, &reconnect, backoff, health)
+    }
+
+    fn status(code: u16) -> TwitterError {
+        TwitterError::Http(StatusCode::from_u16(code).expect("Invalid status code"))
+    }
+
+    #[test]
+    fn reconnect_with_backoff() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let clock = Clock::new_with_now(now.clone());
+        let _clock = clock::set_default(&clock);
+        let mut timer = Timer::new_with_now(ParkThread::new(), clock);
+        let _timer = timer::set_default(&timer.handle());
+
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let health = Health::new(vec!["rust".to_owned()], receiver.get_sink());
+        let mut stream = rate_limited(
+            connections(vec![status(420), status(503), status(401)]),
+            ReconnectConfig {
+                jitter: Some(0.0),
+                ..ReconnectConfig::default()
+            },
+            health.clone(),
+        );
+        let mut advance = |duration| {
+            now.advance(duration);
+            timer
+                .turn(Some(Duration::from_millis(0)))
+                .expect("Could not turn timer");
+        };
+        let backing_off = |health: &Health| match health.status() {
+            StreamStatus::BackingOff { reason, .. } => reason,
+            other => panic!("Expected the stream to back off, got {:?}", other),
+        };
+
+        future::lazy(|| {
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert_eq!(
+                backing_off(&health),
+                "Received HTTP error 420 <unknown status code>"
+            );
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            advance(Duration::from_millis(59_999));
+            assert!(
+                matches!(stream.poll(), Ok(Async::NotReady)),
+                "Rate limited connections back off for a minute"
+            );
+            backing_off(&health);
+
+            advance(Duration::from_millis(1));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert_eq!(health.status(), StreamStatus::Running);
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert!(backing_off(&health).contains("503"));
+
+            advance(Duration::from_millis(5_000));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert!(
+                matches!(stream.poll(), Ok(Async::Ready(None))),
+                "Unauthorized connections are not retried"
+            );
+            match health.status() {
+                StreamStatus::Failed { reason } => assert!(reason.contains("401")),
+                other => panic!("Expected the stream to fail, got {:?}", other),
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("Could not poll stream");
+    }
+
+    #[test]
+    fn keep_backoff_across_connections() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let clock = Clock::new_with_now(now.clone());
+        let _clock = clock::set_default(&clock);
+        let mut timer = Timer::new_with_now(ParkThread::new(), clock);
+        let _timer = timer::set_default(&timer.handle());
+
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let reconnect = ReconnectConfig {
+            jitter: Some(0.0),
+            ..ReconnectConfig::default()
+        };
+        let backoff = Arc::new(Mutex::new(Backoff::new(&reconnect)));
+        let open = |health: &Health| {
+            RateLimitedStream::new(
+                "rust".to_owned(),
+                connections(vec![status(420)]),
+                &reconnect,
+                backoff.clone(),
+                health.clone(),
+            )
+        };
+        let mut advance = |duration| {
+            now.advance(duration);
+            timer
+                .turn(Some(Duration::from_millis(0)))
+                .expect("Could not turn timer");
+        };
+        let remaining = || {
+            backoff
+                .lock()
+                .expect("Backoff lock was poisoned")
+                .pending()
+                .map(|(delay, _)| delay)
+        };
+
+        future::lazy(|| {
+            let health = Health::new(vec!["rust".to_owned()], receiver.get_sink());
+            let mut stream = open(&health);
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert_eq!(remaining(), Some(Duration::from_secs(60)));
+            drop(stream);
+
+            advance(Duration::from_secs(10));
+            let health = Health::new(
+                vec!["rust".to_owned(), "music".to_owned()],
+                receiver.get_sink(),
+            );
+            let mut stream = open(&health);
+            assert!(
+                matches!(health.status(), StreamStatus::BackingOff { .. }),
+                "A new connection waits for the backoff of the previous one"
+            );
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            advance(Duration::from_secs(50));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert_eq!(health.status(), StreamStatus::Running);
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert_eq!(
+                remaining(),
+                Some(Duration::from_secs(120)),
+                "Consecutive rate limits escalate across connections"
+            );
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("Could not poll stream");
+    }
+
+    #[test]
+    fn request_stall_warnings() {
+        let builder = filter_builder(
+            Token::new(
+                "key".to_owned(),
+                "secret".to_owned(),
+                "token".to_owned(),
+                "token secret".to_owned(),
+            ),
+            "rust,music",
+            Duration::from_secs(90),
+        );
+        let request = format!("{:?}", builder);
+        assert!(
+            request.contains("stall_warnings: true"),
+            "FALLING_BEHIND warnings are only sent when requested: {}",
+            request
+        );
+        assert!(request.contains(r#"track: Some("rust,music")"#));
+    }
+
+    #[test]
+    fn reconnect_idle_connection() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        let clock = Clock::new_with_now(now.clone());
+        let _clock = clock::set_default(&clock);
+        let mut timer = Timer::new_with_now(ParkThread::new(), clock);
+        let _timer = timer::set_default(&timer.handle());
+
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let health = Health::new(vec!["rust".to_owned()], receiver.get_sink());
+        let mut stream = rate_limited(
+            connections(vec![TwitterError::TimedOut, TwitterError::TimedOut]),
+            ReconnectConfig {
+                jitter: Some(0.0),
+                idle_timeout_ms: Some(1_000),
+                ..ReconnectConfig::default()
+            },
+            health.clone(),
+        );
+        let mut advance = |duration| {
+            now.advance(duration);
+            timer
+                .turn(Some(Duration::from_millis(0)))
+                .expect("Could not turn timer");
+        };
+
+        future::lazy(|| {
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            match health.status() {
+                StreamStatus::BackingOff { reason, .. } => {
+                    assert_eq!(reason, "No data received for 1000ms")
+                }
+                other => panic!("Expected the stream to back off, got {:?}", other),
+            }
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            advance(Duration::from_millis(250));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            advance(Duration::from_millis(499));
+            assert!(
+                matches!(stream.poll(), Ok(Async::NotReady)),
+                "Consecutive timeouts back off linearly"
+            );
+            advance(Duration::from_millis(1));
+            assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+            assert!(matches!(stream.poll(), Ok(Async::Ready(Some(_)))));
+
+            advance(Duration::from_secs(3600));
+            assert!(
+                matches!(stream.poll(), Ok(Async::NotReady)),
+                "Only the client decides whether the connection is idle"
+            );
+            assert_eq!(health.status(), StreamStatus::Running);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .expect("Could not poll stream");
+    }
 }
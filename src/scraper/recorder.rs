@@ -0,0 +1,198 @@
+use crate::config::RecordConfig;
+use crate::scraper::sanitize_topic;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+const DEFAULT_MAX_FILE_AGE: u64 = 3600;
+
+enum ArchiveWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl ArchiveWriter {
+    fn write_line(&mut self, item: &str) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(writer) => writeln!(writer, "{}", item),
+            ArchiveWriter::Gzip(writer) => writeln!(writer, "{}", item),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(mut writer) => writer.flush(),
+            ArchiveWriter::Gzip(writer) => writer.finish().and_then(|mut inner| inner.flush()),
+        }
+    }
+}
+
+struct ArchiveFile {
+    writer: ArchiveWriter,
+    opened: Instant,
+    written: u64,
+}
+
+/// Archives raw stream payloads to size/time-rotated JSONL files, named after the stream
+pub struct Recorder {
+    stream: String,
+    directory: PathBuf,
+    max_file_size: u64,
+    max_file_age: Duration,
+    compress: bool,
+    sequence: u64,
+    current: Option<ArchiveFile>,
+}
+
+impl Recorder {
+    pub fn new(stream: &str, config: &RecordConfig) -> Self {
+        Self {
+            stream: stream.to_owned(),
+            directory: PathBuf::from(&config.directory),
+            max_file_size: config.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            max_file_age: Duration::from_secs(config.max_file_age.unwrap_or(DEFAULT_MAX_FILE_AGE)),
+            compress: config.compress.unwrap_or(false),
+            sequence: 0,
+            current: None,
+        }
+    }
+
+    /// Append a raw payload to the current archive file, rotating it when required.
+    /// Note: the file size is measured before compression
+    pub fn record(&mut self, item: &str) -> io::Result<()> {
+        let expired = self.current.as_ref().is_some_and(|file| {
+            file.written >= self.max_file_size || file.opened.elapsed() >= self.max_file_age
+        });
+        if expired {
+            self.rotate()?;
+        }
+        if self.current.is_none() {
+            self.current = Some(self.open()?);
+        }
+
+        let file = self.current.as_mut().expect("Archive file was just opened");
+        file.writer.write_line(item)?;
+        file.written += item.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Close the current archive file, the next payload will be written to a new file
+    pub fn rotate(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(file) => file.writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the next archive file, never overwriting existing archives,
+    /// e.g. of another recorder or a previous run within the same second
+    fn open(&mut self) -> io::Result<ArchiveFile> {
+        fs::create_dir_all(&self.directory)?;
+        let (path, file) = loop {
+            let file_name = format!(
+                "{stream}-{time}-{sequence}.jsonl{extension}",
+                stream = sanitize_topic(&self.stream),
+                time = Utc::now().format("%Y%m%dT%H%M%S"),
+                sequence = self.sequence,
+                extension = if self.compress { ".gz" } else { "" }
+            );
+            self.sequence += 1;
+            let path = self.directory.join(file_name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        info!(
+            "[{stream}] Recording raw stream to {path}",
+            stream = &self.stream,
+            path = path.display()
+        );
+
+        let file = BufWriter::new(file);
+        let writer = if self.compress {
+            ArchiveWriter::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            ArchiveWriter::Plain(file)
+        };
+        Ok(ArchiveFile {
+            writer,
+            opened: Instant::now(),
+            written: 0,
+        })
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.rotate() {
+            error!(
+                "[{stream}] Could not close archive file: {err}",
+                stream = &self.stream,
+                err = err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn rotate_compressed_archives() {
+        let directory =
+            std::env::temp_dir().join(format!("sibyl-recorder-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let config = RecordConfig {
+            directory: directory.to_string_lossy().into_owned(),
+            max_file_size: Some(10),
+            max_file_age: None,
+            compress: Some(true),
+        };
+
+        let mut recorder = Recorder::new("rust lang", &config);
+        for item in &["{\"text\":\"first\"}", "{\"text\":\"second\"}"] {
+            recorder.record(item).expect("Could not record item");
+        }
+        drop(recorder);
+        // A restarted recorder does not overwrite the archives of the same second
+        let mut recorder = Recorder::new("rust lang", &config);
+        recorder
+            .record("{\"text\":\"third\"}")
+            .expect("Could not record item");
+        drop(recorder);
+
+        let mut archives = fs::read_dir(&directory)
+            .expect("Could not list archive directory")
+            .map(|entry| {
+                let mut content = String::new();
+                GzDecoder::new(
+                    File::open(entry.expect("Invalid archive entry").path())
+                        .expect("Could not open archive"),
+                )
+                .read_to_string(&mut content)
+                .expect("Could not decompress archive");
+                content
+            })
+            .collect::<Vec<_>>();
+        archives.sort();
+        assert_eq!(
+            archives,
+            vec![
+                "{\"text\":\"first\"}\n",
+                "{\"text\":\"second\"}\n",
+                "{\"text\":\"third\"}\n"
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::scraper::health::Health;
+use crate::scraper::source::{RawStream, StreamError, TweetSource};
+use flate2::read::GzDecoder;
+use futures::sync::mpsc;
+use futures::{stream, Async, Future, Poll, Sink, Stream};
+use log::info;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// Pacing of replayed tweets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pacing {
+    /// Honour the original `timestamp_ms` intervals between tweets
+    Original,
+    /// Replay tweets as fast as they can be processed
+    Fast,
+}
+
+/// Recorded tweets from a newline-delimited JSON dump
+pub struct ReplaySource {
+    path: PathBuf,
+    pacing: Pacing,
+}
+
+impl ReplaySource {
+    pub fn new<P: Into<PathBuf>>(path: P, pacing: Pacing) -> Self {
+        Self {
+            path: path.into(),
+            pacing,
+        }
+    }
+}
+
+/// Number of lines read ahead of the pipeline
+const READ_AHEAD: usize = 1024;
+
+impl TweetSource for ReplaySource {
+    fn stream(&self, topics: &[String], _health: Health) -> RawStream {
+        info!(
+            "Replaying tweets from {path} for topics {topics}",
+            path = self.path.display(),
+            topics = topics.join(", ")
+        );
+        // Reading the dump blocks, so it is read on a dedicated thread instead of the executor
+        let (sender, lines) = mpsc::channel(READ_AHEAD);
+        let path = self.path.clone();
+        match thread::Builder::new()
+            .name("replay".to_owned())
+            .spawn(move || read_lines(&path, sender))
+        {
+            Ok(_) => Box::new(ReplayStream {
+                lines,
+                pacing: self.pacing,
+                previous_timestamp: None,
+                pending: None,
+            }),
+            Err(err) => Box::new(stream::once(Err(StreamError::from(err)))),
+        }
+    }
+
+    /// The dump is replayed regardless of the topics, which only affect the routing of its tweets
+    fn filters_topics(&self) -> bool {
+        false
+    }
+}
+
+/// Send the lines of the dump, until it has been read or the replay is dropped
+fn read_lines(path: &Path, mut sender: mpsc::Sender<io::Result<String>>) {
+    let lines = File::open(path).map(|file| {
+        // Compressed archives are decoded transparently
+        let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        reader.lines()
+    });
+    let lines: Box<dyn Iterator<Item = io::Result<String>>> = match lines {
+        Ok(lines) => Box::new(lines),
+        Err(err) => Box::new(iter::once(Err(err))),
+    };
+    for line in lines {
+        let failed = line.is_err();
+        sender = match sender.send(line).wait() {
+            Ok(sender) => sender,
+            // The replay has been dropped
+            Err(_) => return,
+        };
+        if failed {
+            return;
+        }
+    }
+}
+
+struct ReplayStream {
+    lines: mpsc::Receiver<io::Result<String>>,
+    pacing: Pacing,
+    previous_timestamp: Option<u64>,
+    pending: Option<(String, Delay)>,
+}
+
+/// Extract the `timestamp_ms` field of a raw payload
+fn timestamp_ms(item: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(item)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("timestamp_ms")
+                .and_then(|ts| ts.as_str())
+                .and_then(|ts| ts.parse().ok())
+        })
+}
+
+impl Stream for ReplayStream {
+    type Item = String;
+    type Error = StreamError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some((_, waiter)) = &mut self.pending {
+            match waiter.poll() {
+                Ok(Async::Ready(())) => {
+                    return Ok(Async::Ready(self.pending.take().map(|(item, _)| item)));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => return Err(StreamError::from(err)),
+            }
+        }
+
+        loop {
+            let item = match self.lines.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(Ok(item)))) => item,
+                Ok(Async::Ready(Some(Err(err)))) => return Err(StreamError::from(err)),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    info!("Replay has finished");
+                    return Ok(Async::Ready(None));
+                }
+            };
+            if item.trim().is_empty() {
+                continue;
+            }
+
+            if self.pacing == Pacing::Original {
+                if let Some(timestamp) = timestamp_ms(&item) {
+                    let delay = self
+                        .previous_timestamp
+                        .map(|previous| timestamp.saturating_sub(previous))
+                        .unwrap_or(0);
+                    self.previous_timestamp = Some(timestamp);
+                    if delay > 0 {
+                        let waiter = Delay::new(Instant::now() + Duration::from_millis(delay));
+                        self.pending = Some((item, waiter));
+                        futures::task::current().notify();
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+            return Ok(Async::Ready(Some(item)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metrics_runtime::Receiver;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn replay_all_tweets() {
+        let path =
+            std::env::temp_dir().join(format!("sibyl-replay-test-{}.jsonl", std::process::id()));
+        let mut dump = File::create(&path).expect("Could not create replay dump");
+        dump.write_all(
+            concat!(
+                r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"I love Music","timestamp_ms":"1571257082000"}"#,
+                "\n\n",
+                r#"{"created_at":"Wed Oct 16 20:18:03 +0000 2019","text":"Good food","timestamp_ms":"1571257083000"}"#,
+                "\n",
+                r#"{"limit":{"track":1678,"timestamp_ms":"1571257084000"}}"#,
+                "\n",
+            )
+            .as_bytes(),
+        )
+        .expect("Could not write replay dump");
+
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let topics = vec!["music".to_owned()];
+        let replayed = ReplaySource::new(&path, Pacing::Fast)
+            .stream(&topics, Health::new(topics.clone(), receiver.get_sink()))
+            .collect()
+            .wait()
+            .expect("Could not replay dump");
+        assert_eq!(
+            replayed.len(),
+            3,
+            "Tweets are routed to their topics by the scraper"
+        );
+        assert!(replayed[0].contains("I love Music"));
+        assert!(replayed[1].contains("Good food"));
+        assert!(replayed[2].contains("limit"));
+        fs::remove_file(&path).expect("Could not remove replay dump");
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::RetweetPolicy;
+use crate::tweet::Content;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Number of status ids remembered for deduplication
+const SEEN_STATUSES: usize = 100_000;
+
+/// Least recently used map of status ids to the weight recorded for them
+struct SeenStatuses {
+    capacity: usize,
+    /// Generation at which each id was last seen, along with its recorded weight
+    generations: HashMap<u64, (u64, u32)>,
+    /// Ids in the order they were seen, entries of outdated generations are skipped on eviction
+    order: VecDeque<(u64, u64)>,
+    generation: u64,
+}
+
+impl SeenStatuses {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generations: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Mark the id as most recently seen & raise its recorded weight to `weight`,
+    /// returning the weight recorded before or 0 for unseen ids
+    fn record(&mut self, id: u64, weight: u32) -> u32 {
+        self.generation += 1;
+        let recorded = self
+            .generations
+            .get(&id)
+            .map_or(0, |&(_, recorded)| recorded);
+        self.generations
+            .insert(id, (self.generation, recorded.max(weight)));
+        self.order.push_back((id, self.generation));
+
+        while self.generations.len() > self.capacity || self.order.len() > 2 * self.capacity {
+            match self.order.pop_front() {
+                Some((id, generation))
+                    if self.generations.get(&id).map(|&(seen, _)| seen) == Some(generation) =>
+                {
+                    self.generations.remove(&id);
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+        recorded
+    }
+}
+
+/// Applies a topic's retweet policy to incoming tweets
+pub struct RetweetFilter {
+    policy: RetweetPolicy,
+    seen: Mutex<SeenStatuses>,
+}
+
+impl RetweetFilter {
+    pub fn new(policy: RetweetPolicy) -> Self {
+        Self::with_capacity(policy, SEEN_STATUSES)
+    }
+
+    fn with_capacity(policy: RetweetPolicy, capacity: usize) -> Self {
+        Self {
+            policy,
+            seen: Mutex::new(SeenStatuses::new(capacity)),
+        }
+    }
+
+    /// Weight of the tweet's sentiment sample, or `None` when it should not be scored at all
+    pub fn weight(&self, content: &Content) -> Option<u32> {
+        match (self.policy, &content.retweeted_status) {
+            (RetweetPolicy::Count, _) => Some(1),
+            (RetweetPolicy::Ignore, Some(_)) => None,
+            (RetweetPolicy::Ignore, None) => Some(1),
+            (RetweetPolicy::Weight, original) => {
+                // Every status is scored once, weighted by its number of retweets at the time.
+                // As later retweets report a grown retweet count, they add the difference in weight
+                let status = original.as_deref().unwrap_or(content);
+                let weight = retweet_weight(status.retweet_count.unwrap_or(0));
+                let recorded = match status.id {
+                    Some(id) => self
+                        .seen
+                        .lock()
+                        .expect("Seen statuses lock was poisoned")
+                        .record(id, weight),
+                    None => 0,
+                };
+                Some(weight.saturating_sub(recorded)).filter(|&weight| weight > 0)
+            }
+        }
+    }
+}
+
+/// Weight of a status with the number of retweets, dampened so viral statuses don't drown out all others
+fn retweet_weight(retweet_count: u64) -> u32 {
+    (1.0 + (retweet_count as f64).ln_1p()).round() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tweet::Tweet;
+
+    fn parse_fixture(fixture: &str) -> Content {
+        match serde_json::from_str::<Tweet>(fixture) {
+            Ok(Tweet::Content(content)) => *content,
+            other => panic!("Expected tweet content, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn evict_least_recently_seen() {
+        let mut seen = SeenStatuses::new(2);
+        assert_eq!(seen.record(1, 1), 0);
+        assert_eq!(seen.record(2, 1), 0);
+        assert_eq!(seen.record(1, 3), 1);
+        assert_eq!(seen.record(3, 1), 0);
+        assert_eq!(seen.record(1, 2), 3, "Recently seen id should be retained");
+        assert_eq!(
+            seen.record(2, 1),
+            0,
+            "Least recently seen id should be evicted"
+        );
+    }
+
+    #[test]
+    fn apply_retweet_policies() {
+        let tweet = parse_fixture(include_str!("../../fixtures/tweet.json"));
+        let mut retweet = parse_fixture(include_str!("../../fixtures/retweet_extended.json"));
+
+        let count = RetweetFilter::new(RetweetPolicy::Count);
+        assert_eq!(count.weight(&retweet), Some(1));
+        assert_eq!(count.weight(&retweet), Some(1));
+
+        let ignore = RetweetFilter::new(RetweetPolicy::Ignore);
+        assert_eq!(ignore.weight(&tweet), Some(1));
+        assert_eq!(ignore.weight(&retweet), None);
+
+        let weight = RetweetFilter::with_capacity(RetweetPolicy::Weight, 10);
+        assert_eq!(weight.weight(&tweet), Some(1));
+        assert_eq!(weight.weight(&retweet), Some(7));
+        assert_eq!(
+            weight.weight(&retweet),
+            None,
+            "Retweet storms are deduplicated"
+        );
+        if let Some(original) = retweet.retweeted_status.as_mut() {
+            original.retweet_count = Some(5_000);
+        }
+        assert_eq!(
+            weight.weight(&retweet),
+            Some(3),
+            "A grown retweet count adds the difference in weight"
+        );
+    }
+}
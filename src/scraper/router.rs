@@ -0,0 +1,462 @@
+use crate::scraper::queue::QueueSender;
+use futures::future::{self, Either};
+use futures::Future;
+use metrics_runtime::data::Counter;
+use rand::Rng;
+use serde::de::IgnoredAny;
+use serde_derive::Deserialize;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Stall level of the server side queue above which tweets are shed
+pub const DEFAULT_SHED_THRESHOLD: u8 = 50;
+/// Stall warnings are repeated every ~5 minutes while the stream falls behind,
+/// so the reported level no longer applies once none arrived for longer
+const STALL_WARNING_TTL: Duration = Duration::from_secs(6 * 60);
+
+/// Latest stall level reported by the stream
+struct Stall {
+    percent_full: u8,
+    reported: Instant,
+}
+
+struct Route {
+    topic: String,
+    /// Tokens of every lowercase, space separated term of the topic
+    terms: Vec<Vec<String>>,
+    sender: QueueSender,
+}
+
+impl Route {
+    /// Whether the tweet matches the topic, following the track semantics of the streaming API:
+    /// all terms of the topic have to occur in the tweet as whole words, regardless of case
+    fn matches(&self, tokens: &[&str]) -> bool {
+        self.terms.iter().all(|term| {
+            !term.is_empty()
+                && tokens.windows(term.len()).any(|window| {
+                    window
+                        .iter()
+                        .zip(term)
+                        .all(|(token, term)| token_matches(token, term))
+                })
+        })
+    }
+}
+
+/// Split lowercase text into words, keeping the `#` or `@` of hashtags & mentions.
+/// Punctuation separates words, so `rock` occurs in `rock!` or `#rock`, but not in `rocks`
+fn tokenize(text: &str) -> Vec<&str> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices() {
+        match start {
+            Some(_) if is_word(c) => {}
+            Some(from) => {
+                tokens.push(&text[from..index]);
+                start = None;
+            }
+            None => {}
+        }
+        if start.is_none() && (is_word(c) || c == '#' || c == '@') {
+            start = Some(index);
+        }
+    }
+    if let Some(from) = start {
+        tokens.push(&text[from..]);
+    }
+    tokens.retain(|token| token.chars().any(is_word));
+    tokens
+}
+
+/// A plain word matches hashtags & mentions as well, while a hashtag or mention only matches itself
+fn token_matches(token: &str, term: &str) -> bool {
+    token == term
+        || (!term.starts_with('#')
+            && !term.starts_with('@')
+            && token.get(1..) == Some(term)
+            && (token.starts_with('#') || token.starts_with('@')))
+}
+
+/// Fields of a payload which its routing depends on, borrowed from the raw payload where possible.
+/// Payloads are only decoded in full by the scoring workers, so the shared connection is not held up decoding them
+#[derive(Deserialize)]
+struct Payload<'a> {
+    created_at: Option<IgnoredAny>,
+    #[serde(borrow)]
+    text: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    entities: Entities<'a>,
+    #[serde(borrow)]
+    extended_tweet: Option<ExtendedTweet<'a>>,
+    #[serde(borrow)]
+    user: Option<User<'a>>,
+    #[serde(borrow)]
+    retweeted_status: Option<Box<Payload<'a>>>,
+    #[serde(borrow)]
+    quoted_status: Option<Box<Payload<'a>>>,
+    warning: Option<Warning>,
+}
+
+impl Payload<'_> {
+    /// Whether the payload is a tweet, as opposed to a control message
+    fn is_tweet(&self) -> bool {
+        self.created_at.is_some() && self.text.is_some()
+    }
+
+    /// Complete body of the tweet, retweets resolve to the body of their original status
+    fn full_text(&self) -> &str {
+        match (&self.retweeted_status, &self.extended_tweet) {
+            (Some(original), _) => original.full_text(),
+            (None, Some(extended)) => &extended.full_text,
+            (None, None) => self.text.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Entities<'a> {
+    #[serde(borrow, default)]
+    hashtags: Vec<Hashtag<'a>>,
+    #[serde(borrow, default)]
+    user_mentions: Vec<User<'a>>,
+    #[serde(borrow, default)]
+    urls: Vec<Url<'a>>,
+}
+
+#[derive(Deserialize)]
+struct ExtendedTweet<'a> {
+    #[serde(borrow)]
+    full_text: Cow<'a, str>,
+    #[serde(borrow)]
+    entities: Option<Entities<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Hashtag<'a> {
+    #[serde(borrow)]
+    text: Cow<'a, str>,
+}
+
+#[derive(Deserialize)]
+struct User<'a> {
+    #[serde(borrow, default)]
+    screen_name: Cow<'a, str>,
+}
+
+#[derive(Deserialize)]
+struct Url<'a> {
+    #[serde(borrow)]
+    url: Cow<'a, str>,
+    #[serde(borrow)]
+    expanded_url: Option<Cow<'a, str>>,
+}
+
+#[derive(Deserialize)]
+struct Warning {
+    percent_full: Option<u8>,
+}
+
+/// Classifies the payloads of the shared filter connection to the topics they match.
+/// A tweet can match several topics, while control messages are delivered to every topic
+pub struct Router {
+    routes: RwLock<Vec<Route>>,
+    /// Number of tweets which did not match any topic
+    unmatched: Counter,
+    stall: Mutex<Option<Stall>>,
+    shed_threshold: AtomicUsize,
+    /// Number of tweets skipped to catch up with the stream
+    shed: Counter,
+}
+
+impl Router {
+    pub fn new(unmatched: Counter, shed: Counter, shed_threshold: u8) -> Self {
+        Self {
+            routes: RwLock::new(Vec::new()),
+            unmatched,
+            stall: Mutex::new(None),
+            shed_threshold: AtomicUsize::new(shed_threshold as usize),
+            shed,
+        }
+    }
+
+    pub fn set_shed_threshold(&self, shed_threshold: u8) {
+        self.shed_threshold
+            .store(shed_threshold as usize, Ordering::Relaxed);
+    }
+
+    /// Share of the tweets to shed, growing from 0 at the threshold to all tweets once the server side queue is full
+    fn shed_fraction(&self) -> f64 {
+        let threshold = self.shed_threshold.load(Ordering::Relaxed) as f64;
+        match &*self.stall.lock().expect("Stall lock was poisoned") {
+            Some(stall)
+                if stall.reported.elapsed() < STALL_WARNING_TTL
+                    && f64::from(stall.percent_full) > threshold =>
+            {
+                (f64::from(stall.percent_full) - threshold) / (100.0 - threshold)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Start routing the payloads matching the topic to its queue,
+    /// replacing any previous route of the topic
+    pub fn add(&self, topic: &str, sender: QueueSender) {
+        let mut routes = self.routes.write().expect("Route lock was poisoned");
+        routes.retain(|route| route.topic != topic);
+        routes.push(Route {
+            topic: topic.to_owned(),
+            terms: topic
+                .to_lowercase()
+                .split_whitespace()
+                .map(|term| tokenize(term).into_iter().map(str::to_owned).collect())
+                .collect(),
+            sender,
+        });
+    }
+
+    /// Stop routing payloads to the topic, which closes its queue
+    pub fn remove(&self, topic: &str) {
+        self.routes
+            .write()
+            .expect("Route lock was poisoned")
+            .retain(|route| route.topic != topic);
+    }
+
+    /// All routed topics, in sorted order
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics = self
+            .routes
+            .read()
+            .expect("Route lock was poisoned")
+            .iter()
+            .map(|route| route.topic.clone())
+            .collect::<Vec<_>>();
+        topics.sort();
+        topics
+    }
+
+    /// Deliver the payload to every matching topic.
+    /// Completes once all topic queues have accepted the payload, subject to their overflow policy.
+    /// While a stall warning reports the stream is falling behind, a share of the tweets is shed
+    /// so the server side queue drains before the server disconnects the stream
+    pub fn route(&self, item: String) -> impl Future<Item = (), Error = ()> {
+        let text = match serde_json::from_str::<Payload>(&item) {
+            Ok(payload)
+                if payload.is_tweet() && rand::thread_rng().gen::<f64>() < self.shed_fraction() =>
+            {
+                self.shed.record(1);
+                return Either::A(future::ok(()));
+            }
+            Ok(payload) if payload.is_tweet() => Some(searchable_text(&payload)),
+            Ok(Payload {
+                warning: Some(warning),
+                ..
+            }) => {
+                if let Some(percent_full) = warning.percent_full {
+                    *self.stall.lock().expect("Stall lock was poisoned") = Some(Stall {
+                        percent_full,
+                        reported: Instant::now(),
+                    });
+                }
+                None
+            }
+            // Control messages & undecodable payloads are handled by every topic
+            _ => None,
+        };
+        let tokens = text.as_deref().map(tokenize);
+        let senders = self
+            .routes
+            .read()
+            .expect("Route lock was poisoned")
+            .iter()
+            .filter(|route| tokens.as_ref().map_or(true, |tokens| route.matches(tokens)))
+            .map(|route| route.sender.clone())
+            .collect::<Vec<_>>();
+        if senders.is_empty() {
+            self.unmatched.record(1);
+        }
+
+        // Only tweets may be discarded by the overflow policy of a queue
+        let droppable = text.is_some();
+        // Topics which have been unsubscribed from in the meantime are skipped
+        Either::B(
+            future::join_all(
+                senders
+                    .into_iter()
+                    .map(move |sender| sender.send(item.clone(), droppable)),
+            )
+            .map(|_: Vec<()>| ()),
+        )
+    }
+}
+
+/// Lowercase text the streaming API matches topics against:
+/// the body, hashtags, mentioned screen names & urls of the tweet, along with its retweeted or quoted status
+fn searchable_text(payload: &Payload) -> String {
+    let mut text = vec![payload.full_text().to_owned()];
+    let entities = payload
+        .extended_tweet
+        .as_ref()
+        .and_then(|extended| extended.entities.as_ref())
+        .unwrap_or(&payload.entities);
+    text.extend(entities.hashtags.iter().map(|tag| format!("#{}", tag.text)));
+    text.extend(
+        entities
+            .user_mentions
+            .iter()
+            .map(|mention| format!("@{}", mention.screen_name)),
+    );
+    text.extend(
+        entities
+            .urls
+            .iter()
+            .map(|url| url.expanded_url.as_ref().unwrap_or(&url.url).to_string()),
+    );
+    if let Some(user) = &payload.user {
+        text.push(format!("@{}", user.screen_name));
+    }
+    text.extend(
+        payload
+            .retweeted_status
+            .iter()
+            .chain(payload.quoted_status.iter())
+            .map(|original| searchable_text(original)),
+    );
+    text.join(" ").to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::OverflowPolicy;
+    use crate::scraper::queue::{self, QueueReceiver};
+    use futures::Stream;
+    use metrics_runtime::{Receiver, Sink};
+
+    fn add_route(router: &Router, sink: &mut Sink, topic: &str) -> QueueReceiver {
+        let (sender, receiver) = queue::bounded(
+            10,
+            OverflowPolicy::Block,
+            sink.gauge("queue_depth"),
+            sink.counter("tweets_dropped"),
+        );
+        router.add(topic, sender);
+        receiver
+    }
+
+    #[test]
+    fn route_to_matching_topics() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let mut sink = receiver.get_sink();
+        let router = Router::new(
+            sink.counter("tweets_unmatched"),
+            sink.counter("tweets_shed"),
+            DEFAULT_SHED_THRESHOLD,
+        );
+        let music = add_route(&router, &mut sink, "rock music");
+        let food = add_route(&router, &mut sink, "food");
+        assert_eq!(router.topics(), vec!["food", "rock music"]);
+
+        for item in &[
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"Music to ROCK to!"}"#,
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"Good food","entities":{"hashtags":[{"text":"rock"}]}}"#,
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"Rock music & food"}"#,
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"Musicians that ROCKS, seafood"}"#,
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"Nothing to see"}"#,
+            r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"RT","retweeted_status":{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"\"Rock\" \u00e0 la music"}}"#,
+            r#"{"delete":{"status":{"id":1,"id_str":"1","user_id":2,"user_id_str":"2"}}}"#,
+        ] {
+            router
+                .route((*item).to_owned())
+                .wait()
+                .expect("Could not route payload");
+        }
+        drop(router);
+
+        let music = music
+            .collect()
+            .wait()
+            .expect("Could not collect routed payloads");
+        assert_eq!(
+            music.len(),
+            4,
+            "Every term of the topic should match as a whole word"
+        );
+        assert!(music[0].contains("Music to ROCK to!"));
+        assert!(music[1].contains("Rock music & food"));
+        assert!(
+            music[2].contains("retweeted_status"),
+            "Escaped text should match"
+        );
+        assert!(music[3].contains("delete"));
+        let food = food
+            .collect()
+            .wait()
+            .expect("Could not collect routed payloads");
+        assert_eq!(food.len(), 3);
+        assert!(food[0].contains("Good food"));
+    }
+
+    #[test]
+    fn match_whole_words() {
+        let tokens = tokenize("#rustlang, @rust's http://example.com/rust");
+        assert_eq!(
+            tokens,
+            vec!["#rustlang", "@rust", "s", "http", "example", "com", "rust"]
+        );
+        assert!(tokenize("--- #").is_empty());
+        assert!(token_matches("#rust", "rust"));
+        assert!(token_matches("@rust", "rust"));
+        assert!(!token_matches("rust", "#rust"));
+        assert!(!token_matches("@rust", "#rust"));
+        assert!(!token_matches("rusty", "rust"));
+    }
+
+    #[test]
+    fn shed_while_stalled() {
+        let receiver = Receiver::builder()
+            .build()
+            .expect("failed to create metrics receiver");
+        let mut sink = receiver.get_sink();
+        let router = Router::new(
+            sink.counter("tweets_unmatched"),
+            sink.counter("tweets_shed"),
+            0,
+        );
+        let music = add_route(&router, &mut sink, "music");
+
+        let tweet = r#"{"created_at":"Wed Oct 16 20:18:02 +0000 2019","text":"I love music"}"#;
+        for item in &[
+            tweet,
+            r#"{"warning":{"code":"FALLING_BEHIND","message":"behind","percent_full":100}}"#,
+            tweet,
+        ] {
+            router
+                .route((*item).to_owned())
+                .wait()
+                .expect("Could not route payload");
+        }
+        router.set_shed_threshold(100);
+        router
+            .route(tweet.to_owned())
+            .wait()
+            .expect("Could not route payload");
+        drop(router);
+
+        let music = music
+            .collect()
+            .wait()
+            .expect("Could not collect routed payloads");
+        assert_eq!(music.len(), 3, "Tweets should be shed while stalled");
+        assert!(
+            music[1].contains("FALLING_BEHIND"),
+            "Warnings are never shed"
+        );
+        assert!(music[2].contains("I love music"));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config::ReconnectConfig;
+use crate::scraper::backoff::Backoff;
+use crate::scraper::health::Health;
+use crate::scraper::rate_controlled_stream::RateLimitedStream;
+use futures::Stream;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use twitter_stream::Token;
+
+/// Reasons a stream of tweets failed, shared by all tweet sources
+#[derive(Debug)]
+pub enum StreamError {
+    /// Error of the Twitter streaming API client
+    Api(twitter_stream::error::Error),
+    /// A recorded dump could not be read
+    Io(io::Error),
+    /// The timer pacing or delaying the stream failed
+    Timer(tokio_timer::Error),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Api(err) => write!(f, "Twitter API error: {}", err),
+            StreamError::Io(err) => write!(f, "Could not read tweets: {}", err),
+            StreamError::Timer(err) => write!(f, "Stream timer failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Api(err) => Some(err),
+            StreamError::Io(err) => Some(err),
+            StreamError::Timer(err) => Some(err),
+        }
+    }
+}
+
+impl From<twitter_stream::error::Error> for StreamError {
+    fn from(err: twitter_stream::error::Error) -> Self {
+        StreamError::Api(err)
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> Self {
+        StreamError::Io(err)
+    }
+}
+
+impl From<tokio_timer::Error> for StreamError {
+    fn from(err: tokio_timer::Error) -> Self {
+        StreamError::Timer(err)
+    }
+}
+
+/// Stream of raw JSON encoded tweet payloads
+pub type RawStream = Box<dyn Stream<Item = String, Error = StreamError> + Send>;
+
+/// Provider of raw tweet payloads for a set of topics
+pub trait TweetSource: Send + Sync {
+    /// Open a new stream of raw JSON payloads matching any of the specified topics.
+    /// Payloads are classified to their topics by the consumer of the stream,
+    /// while the stream reports its status through `health`
+    fn stream(&self, topics: &[String], health: Health) -> RawStream;
+
+    /// Whether the stream only delivers payloads matching its topics,
+    /// so it has to be reopened when the topics change
+    fn filters_topics(&self) -> bool {
+        true
+    }
+}
+
+/// Live tweets using the Twitter streaming API
+pub struct TwitterSource {
+    api_token: Token<String, String>,
+    reconnect: ReconnectConfig,
+    /// Backoff of the current connection, which the next connection continues
+    backoff: Arc<Mutex<Backoff>>,
+}
+
+impl TwitterSource {
+    pub fn new(api_token: Token<String, String>, reconnect: ReconnectConfig) -> Self {
+        Self {
+            api_token,
+            backoff: Arc::new(Mutex::new(Backoff::new(&reconnect))),
+            reconnect,
+        }
+    }
+}
+
+impl TweetSource for TwitterSource {
+    fn stream(&self, topics: &[String], health: Health) -> RawStream {
+        Box::new(RateLimitedStream::from_topics(
+            self.api_token.clone(),
+            topics,
+            &self.reconnect,
+            self.backoff.clone(),
+            health,
+        ))
+    }
+}
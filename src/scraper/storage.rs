@@ -0,0 +1,693 @@
+use crate::config::StorageConfig;
+use crate::scraper::metrics::{Resolution, Rollup, Sample};
+use crate::scraper::sanitize_topic;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// Reasons persisting or restoring samples failed
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// A stored file does not have a valid header
+    Corrupt {
+        topic: String,
+        path: PathBuf,
+    },
+    /// A thread panicked while holding the lock of the named structure
+    Poisoned(&'static str),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "Storage I/O error: {}", err),
+            StorageError::Corrupt { topic, path } => write!(
+                f,
+                "Invalid segment header for topic {} in {}",
+                topic,
+                path.display()
+            ),
+            StorageError::Poisoned(name) => write!(f, "{} lock was poisoned", name),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(err) => Some(err),
+            StorageError::Corrupt { .. } | StorageError::Poisoned(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// Persistence backend for time series samples
+pub trait Storage: Send + Sync {
+    /// Load all persisted samples of a topic
+    fn load(&self, topic: &str) -> Result<Vec<Sample>>;
+    /// Persist additional samples of a topic
+    fn append(&self, topic: &str, samples: &[Sample]) -> Result<()>;
+    /// Discard previously persisted samples, e.g. of deleted tweets
+    fn retract(&self, topic: &str, samples: &[Sample]) -> Result<()>;
+    /// Discard persisted samples older than the specified epoch time.
+    /// Backends may retain older samples at their own discretion
+    fn truncate(&self, topic: &str, before: i64) -> Result<()>;
+    /// Discard all persisted samples & rollups of a topic
+    fn remove(&self, topic: &str) -> Result<()>;
+    /// Load the persisted rollups of a topic at the resolution
+    fn load_rollups(&self, topic: &str, resolution: Resolution) -> Result<Vec<Rollup>>;
+    /// Replace the persisted rollups of a topic at the resolution
+    fn store_rollups(&self, topic: &str, resolution: Resolution, rollups: &[Rollup]) -> Result<()>;
+    /// Flush all pending writes to disk.
+    /// Backends may buffer appended samples until then, or until the next truncation of the topic
+    fn flush(&self) -> Result<()>;
+}
+
+/// Create the storage backend selected in the configuration
+pub fn from_config(config: Option<&StorageConfig>) -> Result<Arc<dyn Storage>> {
+    match config {
+        None | Some(StorageConfig::Memory) => Ok(Arc::new(MemoryStorage)),
+        Some(StorageConfig::Segment { directory }) => {
+            Ok(Arc::new(SegmentStorage::open(directory)?))
+        }
+    }
+}
+
+/// Volatile storage, samples only live as long as the process
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&self, _topic: &str) -> Result<Vec<Sample>> {
+        Ok(Vec::new())
+    }
+
+    fn append(&self, _topic: &str, _samples: &[Sample]) -> Result<()> {
+        Ok(())
+    }
+
+    fn retract(&self, _topic: &str, _samples: &[Sample]) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, _topic: &str, _before: i64) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(&self, _topic: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn load_rollups(&self, _topic: &str, _resolution: Resolution) -> Result<Vec<Rollup>> {
+        Ok(Vec::new())
+    }
+
+    fn store_rollups(
+        &self,
+        _topic: &str,
+        _resolution: Resolution,
+        _rollups: &[Rollup],
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+const SEGMENT_MAGIC: &[u8; 7] = b"SIBYLSG";
+const SEGMENT_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+const RECORD_SIZE: usize = 20;
+const ROLLUP_MAGIC: &[u8; 7] = b"SIBYLRU";
+const ROLLUP_VERSION: u8 = 1;
+const ROLLUP_RECORD_SIZE: usize = 40;
+/// Maximum number of samples per segment file
+const SEGMENT_CAPACITY: usize = 65536;
+
+struct Segment {
+    writer: BufWriter<File>,
+    sequence: u64,
+    samples: usize,
+}
+
+/// Append-only on-disk storage.
+/// Each topic is stored in a directory of numbered segment files containing an 8 byte header
+/// followed by fixed size little-endian `(time, value, weight)` records.
+/// Retracted samples are recorded in a tombstone file of the same format, which masks them on load.
+/// Rollups are kept in a file per resolution of little-endian `(time, count, sum, min, max)` records,
+/// which is replaced whenever they are stored
+pub struct SegmentStorage {
+    directory: PathBuf,
+    /// Segment being written to per topic, each locked separately so topics are written concurrently
+    segments: Mutex<HashMap<String, Arc<Mutex<Option<Segment>>>>>,
+}
+
+impl SegmentStorage {
+    pub fn open<P: Into<PathBuf>>(directory: P) -> Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            segments: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn topic_directory(&self, topic: &str) -> PathBuf {
+        self.directory.join(sanitize_topic(topic))
+    }
+
+    /// Segment being written to for the topic, which has to be locked for any file operation of the topic
+    fn topic_segment(&self, topic: &str) -> Result<Arc<Mutex<Option<Segment>>>> {
+        let mut segments = self
+            .segments
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment storage"))?;
+        Ok(segments.entry(topic.to_owned()).or_default().clone())
+    }
+
+    /// Sequence numbers of all segments of a topic, in ascending order
+    fn segment_sequences(&self, topic: &str) -> Result<Vec<u64>> {
+        let directory = self.topic_directory(topic);
+        if !directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut sequences = fs::read_dir(directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                match path.extension() {
+                    Some(ext) if ext == "seg" => path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<u64>().ok()),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        sequences.sort_unstable();
+        Ok(sequences)
+    }
+
+    fn segment_path(&self, topic: &str, sequence: u64) -> PathBuf {
+        self.topic_directory(topic)
+            .join(format!("{:010}.seg", sequence))
+    }
+
+    fn tombstone_path(&self, topic: &str) -> PathBuf {
+        self.topic_directory(topic).join("retracted.tomb")
+    }
+
+    fn rollup_path(&self, topic: &str, resolution: Resolution) -> PathBuf {
+        let name = match resolution {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "minute",
+            Resolution::Hour => "hour",
+        };
+        self.topic_directory(topic)
+            .join(format!("{}.rollups", name))
+    }
+
+    fn read_segment(&self, topic: &str, sequence: u64) -> Result<Vec<Sample>> {
+        read_records(&self.segment_path(topic, sequence), topic)
+    }
+
+    fn read_tombstones(&self, topic: &str) -> Result<Vec<Sample>> {
+        let path = self.tombstone_path(topic);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        read_records(&path, topic)
+    }
+
+    /// Replace the tombstones of a topic
+    fn write_tombstones(&self, topic: &str, tombstones: &[Sample]) -> Result<()> {
+        let path = self.tombstone_path(topic);
+        let staged = path.with_extension("tomb.tmp");
+        let mut writer = BufWriter::new(File::create(&staged)?);
+        writer.write_all(SEGMENT_MAGIC)?;
+        writer.write_all(&[SEGMENT_VERSION])?;
+        for tombstone in tombstones {
+            writer.write_all(&encode_sample(tombstone))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(staged, path)?;
+        Ok(())
+    }
+
+    /// Append tombstones to those of a topic.
+    /// A trailing partial record of an interrupted write is discarded first, so the records stay aligned
+    fn append_tombstones(&self, topic: &str, tombstones: &[Sample]) -> Result<()> {
+        fs::create_dir_all(self.topic_directory(topic))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(self.tombstone_path(topic))?;
+        let len = file.metadata()?.len() as usize;
+        let mut records = Vec::with_capacity(HEADER_SIZE + tombstones.len() * RECORD_SIZE);
+        if len < HEADER_SIZE {
+            file.set_len(0)?;
+            records.extend_from_slice(SEGMENT_MAGIC);
+            records.push(SEGMENT_VERSION);
+        } else {
+            file.set_len((len - (len - HEADER_SIZE) % RECORD_SIZE) as u64)?;
+        }
+        for tombstone in tombstones {
+            records.extend_from_slice(&encode_sample(tombstone));
+        }
+        Ok(file.write_all(&records)?)
+    }
+
+    /// Open the last segment of a topic for appending, or a new one once it is full.
+    /// A trailing partial record of an interrupted write is discarded first, so the records stay aligned
+    fn open_segment(&self, topic: &str) -> Result<Segment> {
+        let last = self.segment_sequences(topic)?.last().copied();
+        if let Some(sequence) = last {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(self.segment_path(topic, sequence))?;
+            let len = file.metadata()?.len() as usize;
+            let mut header = [0u8; HEADER_SIZE];
+            if len < HEADER_SIZE {
+                // The header was never written completely
+                file.set_len(0)?;
+                let mut writer = BufWriter::new(file);
+                writer.write_all(SEGMENT_MAGIC)?;
+                writer.write_all(&[SEGMENT_VERSION])?;
+                return Ok(Segment {
+                    writer,
+                    sequence,
+                    samples: 0,
+                });
+            }
+            file.read_exact(&mut header)?;
+            let samples = (len - HEADER_SIZE) / RECORD_SIZE;
+            if header[..SEGMENT_MAGIC.len()] == SEGMENT_MAGIC[..]
+                && header[SEGMENT_MAGIC.len()] == SEGMENT_VERSION
+                && samples < SEGMENT_CAPACITY
+            {
+                file.set_len((HEADER_SIZE + samples * RECORD_SIZE) as u64)?;
+                return Ok(Segment {
+                    writer: BufWriter::new(file),
+                    sequence,
+                    samples,
+                });
+            }
+        }
+        self.create_segment(topic, last.map_or(0, |sequence| sequence + 1))
+    }
+
+    /// Open a new segment for writing
+    fn create_segment(&self, topic: &str, sequence: u64) -> Result<Segment> {
+        fs::create_dir_all(self.topic_directory(topic))?;
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(self.segment_path(topic, sequence))?,
+        );
+        writer.write_all(SEGMENT_MAGIC)?;
+        writer.write_all(&[SEGMENT_VERSION])?;
+        Ok(Segment {
+            writer,
+            sequence,
+            samples: 0,
+        })
+    }
+}
+
+impl Storage for SegmentStorage {
+    fn load(&self, topic: &str) -> Result<Vec<Sample>> {
+        let segment = self.topic_segment(topic)?;
+        let mut segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        if let Some(segment) = segment.as_mut() {
+            segment.writer.flush()?;
+        }
+
+        let mut samples = Vec::new();
+        for sequence in self.segment_sequences(topic)? {
+            samples.extend(self.read_segment(topic, sequence)?);
+        }
+        // Every tombstone masks a single matching sample
+        let mut tombstones = HashMap::new();
+        for tombstone in self.read_tombstones(topic)? {
+            *tombstones.entry(tombstone).or_insert(0) += 1;
+        }
+        samples.retain(|sample| match tombstones.get_mut(sample) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        });
+        Ok(samples)
+    }
+
+    /// Samples are buffered until the segment is full, flushed or the topic is truncated
+    fn append(&self, topic: &str, samples: &[Sample]) -> Result<()> {
+        let segment = self.topic_segment(topic)?;
+        let mut segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        for sample in samples {
+            let rotate = segment
+                .as_ref()
+                .map_or(true, |segment| segment.samples >= SEGMENT_CAPACITY);
+            if rotate {
+                *segment = Some(match segment.take() {
+                    Some(mut previous) => {
+                        previous.writer.flush()?;
+                        self.create_segment(topic, previous.sequence + 1)?
+                    }
+                    None => self.open_segment(topic)?,
+                });
+            }
+            let segment = segment.as_mut().expect("Segment was just opened");
+            segment.writer.write_all(&encode_sample(sample))?;
+            segment.samples += 1;
+        }
+        Ok(())
+    }
+
+    fn retract(&self, topic: &str, samples: &[Sample]) -> Result<()> {
+        let segment = self.topic_segment(topic)?;
+        let _segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        self.append_tombstones(topic, samples)
+    }
+
+    /// Remove entire segments which only contain samples before the specified time.
+    /// The segment currently being written to is always kept, while its buffered samples are flushed.
+    /// The tombstones are compacted as well: those before the specified time are discarded,
+    /// once their samples are no longer stored in any segment
+    fn truncate(&self, topic: &str, before: i64) -> Result<()> {
+        let segment = self.topic_segment(topic)?;
+        let mut segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        if let Some(segment) = segment.as_mut() {
+            segment.writer.flush()?;
+        }
+        let active = segment.as_ref().map(|segment| segment.sequence);
+        for sequence in self.segment_sequences(topic)? {
+            let expired = Some(sequence) != active
+                && self
+                    .read_segment(topic, sequence)?
+                    .iter()
+                    .all(|sample| sample.time < before);
+            if !expired {
+                break;
+            }
+            fs::remove_file(self.segment_path(topic, sequence))?;
+        }
+
+        let tombstones = self.read_tombstones(topic)?;
+        if tombstones.iter().any(|tombstone| tombstone.time < before) {
+            let mut oldest = before;
+            for sequence in self.segment_sequences(topic)? {
+                for sample in self.read_segment(topic, sequence)? {
+                    oldest = oldest.min(sample.time);
+                }
+            }
+            if tombstones.iter().any(|tombstone| tombstone.time < oldest) {
+                let retained = tombstones
+                    .into_iter()
+                    .filter(|tombstone| tombstone.time >= oldest)
+                    .collect::<Vec<_>>();
+                self.write_tombstones(topic, &retained)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, topic: &str) -> Result<()> {
+        let segment = self.topic_segment(topic)?;
+        let mut segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        *segment = None;
+        let directory = self.topic_directory(topic);
+        if directory.exists() {
+            fs::remove_dir_all(directory)?;
+        }
+        Ok(())
+    }
+
+    fn load_rollups(&self, topic: &str, resolution: Resolution) -> Result<Vec<Rollup>> {
+        let segment = self.topic_segment(topic)?;
+        let _segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        let path = self.rollup_path(topic, resolution);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut content = Vec::new();
+        File::open(&path)?.read_to_end(&mut content)?;
+        if content.len() < HEADER_SIZE
+            || &content[..ROLLUP_MAGIC.len()] != ROLLUP_MAGIC
+            || content[ROLLUP_MAGIC.len()] != ROLLUP_VERSION
+        {
+            return Err(StorageError::Corrupt {
+                topic: topic.to_owned(),
+                path,
+            });
+        }
+        Ok(content[HEADER_SIZE..]
+            .chunks_exact(ROLLUP_RECORD_SIZE)
+            .map(decode_rollup)
+            .collect())
+    }
+
+    fn store_rollups(&self, topic: &str, resolution: Resolution, rollups: &[Rollup]) -> Result<()> {
+        let segment = self.topic_segment(topic)?;
+        let _segment = segment
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment"))?;
+        fs::create_dir_all(self.topic_directory(topic))?;
+        let path = self.rollup_path(topic, resolution);
+        let staged = path.with_extension("rollups.tmp");
+        let mut writer = BufWriter::new(File::create(&staged)?);
+        writer.write_all(ROLLUP_MAGIC)?;
+        writer.write_all(&[ROLLUP_VERSION])?;
+        for rollup in rollups {
+            writer.write_all(&encode_rollup(rollup))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(staged, path)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let segments = self
+            .segments
+            .lock()
+            .map_err(|_| StorageError::Poisoned("Segment storage"))?
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        segments.iter().try_for_each(|segment| {
+            let mut segment = segment
+                .lock()
+                .map_err(|_| StorageError::Poisoned("Segment"))?;
+            match segment.as_mut() {
+                Some(segment) => {
+                    segment.writer.flush()?;
+                    Ok(segment.writer.get_ref().sync_data()?)
+                }
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+/// Read the records of a segment or tombstone file
+fn read_records(path: &Path, topic: &str) -> Result<Vec<Sample>> {
+    let mut content = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+    if content.len() < HEADER_SIZE {
+        // File was created but its header was never written completely
+        return Ok(Vec::new());
+    }
+    if &content[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC
+        || content[SEGMENT_MAGIC.len()] != SEGMENT_VERSION
+    {
+        return Err(StorageError::Corrupt {
+            topic: topic.to_owned(),
+            path: path.to_owned(),
+        });
+    }
+    // A trailing partial record is the result of an interrupted write & is ignored
+    Ok(content[HEADER_SIZE..]
+        .chunks_exact(RECORD_SIZE)
+        .map(decode_sample)
+        .collect())
+}
+
+fn encode_sample(sample: &Sample) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..8].copy_from_slice(&sample.time.to_le_bytes());
+    record[8..16].copy_from_slice(&sample.value.to_le_bytes());
+    record[16..].copy_from_slice(&sample.weight.to_le_bytes());
+    record
+}
+
+fn decode_sample(record: &[u8]) -> Sample {
+    let mut time = [0u8; 8];
+    let mut value = [0u8; 8];
+    let mut weight = [0u8; 4];
+    time.copy_from_slice(&record[..8]);
+    value.copy_from_slice(&record[8..16]);
+    weight.copy_from_slice(&record[16..]);
+    Sample {
+        time: i64::from_le_bytes(time),
+        value: i64::from_le_bytes(value),
+        weight: u32::from_le_bytes(weight),
+    }
+}
+
+fn encode_rollup(rollup: &Rollup) -> [u8; ROLLUP_RECORD_SIZE] {
+    let mut record = [0u8; ROLLUP_RECORD_SIZE];
+    record[..8].copy_from_slice(&rollup.time.to_le_bytes());
+    record[8..16].copy_from_slice(&rollup.count.to_le_bytes());
+    record[16..24].copy_from_slice(&rollup.sum.to_le_bytes());
+    record[24..32].copy_from_slice(&rollup.min.to_le_bytes());
+    record[32..].copy_from_slice(&rollup.max.to_le_bytes());
+    record
+}
+
+fn decode_rollup(record: &[u8]) -> Rollup {
+    let field = |index: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&record[index * 8..(index + 1) * 8]);
+        bytes
+    };
+    let count = u64::from_le_bytes(field(1));
+    let sum = i64::from_le_bytes(field(2));
+    Rollup {
+        time: i64::from_le_bytes(field(0)),
+        count,
+        sum,
+        min: i64::from_le_bytes(field(3)),
+        max: i64::from_le_bytes(field(4)),
+        mean: if count == 0 {
+            0.0
+        } else {
+            sum as f64 / count as f64
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reload_segments() {
+        let directory =
+            std::env::temp_dir().join(format!("sibyl-storage-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let samples = vec![
+            Sample {
+                time: 1,
+                value: -3,
+                weight: 1,
+            },
+            Sample {
+                time: 2,
+                value: 5,
+                weight: 1,
+            },
+        ];
+
+        let storage = SegmentStorage::open(&directory).expect("Could not open storage");
+        storage
+            .append("music", &samples)
+            .expect("Could not append samples");
+        drop(storage);
+
+        // Simulate a write interrupted by a crash
+        OpenOptions::new()
+            .append(true)
+            .open(directory.join("music").join("0000000000.seg"))
+            .and_then(|mut segment| segment.write_all(&[1, 2, 3]))
+            .expect("Could not corrupt segment");
+
+        let storage = SegmentStorage::open(&directory).expect("Could not reopen storage");
+        assert_eq!(storage.load("music").expect("Could not load"), samples);
+        storage
+            .append(
+                "music",
+                &[Sample {
+                    time: 3,
+                    value: 0,
+                    weight: 1,
+                }],
+            )
+            .expect("Could not append samples");
+        assert_eq!(storage.load("music").expect("Could not load").len(), 3);
+        assert_eq!(
+            storage
+                .segment_sequences("music")
+                .expect("Could not list segments"),
+            vec![0],
+            "The last segment is reused after a restart"
+        );
+        assert!(storage.load("food").expect("Could not load").is_empty());
+        assert_ne!(
+            storage.topic_directory("rock music"),
+            storage.topic_directory("rock_music")
+        );
+
+        // Retracted samples are masked, a partially written tombstone is discarded
+        storage
+            .retract("music", &samples[1..])
+            .expect("Could not retract samples");
+        OpenOptions::new()
+            .append(true)
+            .open(directory.join("music").join("retracted.tomb"))
+            .and_then(|mut tombstones| tombstones.write_all(&[1, 2, 3]))
+            .expect("Could not corrupt tombstones");
+        storage
+            .retract("music", &samples[..1])
+            .expect("Could not retract samples");
+        assert_eq!(
+            storage.load("music").expect("Could not load"),
+            vec![Sample {
+                time: 3,
+                value: 0,
+                weight: 1
+            }]
+        );
+
+        // Only complete segments can be truncated
+        storage.truncate("music", 10).expect("Could not truncate");
+        assert_eq!(
+            storage.load("music").expect("Could not load"),
+            vec![Sample {
+                time: 3,
+                value: 0,
+                weight: 1
+            }]
+        );
+    }
+}
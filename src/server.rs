@@ -1,15 +1,19 @@
-use crate::config::ServerConfig;
-use crate::scraper::metrics::{Sample, TimeSeries};
+use crate::config::{self, Secret, ServerConfig};
+use crate::scraper::metrics::{Aggregation, Bucket, Points, Resolution, TimeSeries};
+use crate::scraper::{Scraper, TopicError};
 use actix_files as fs;
 use actix_web::dev::Server;
 use actix_web::http::StatusCode;
-use actix_web::{get, guard, middleware, web, App, HttpResponse, HttpServer};
+use actix_web::{
+    delete, get, guard, middleware, post, web, App, HttpRequest, HttpResponse, HttpServer,
+};
 use chrono::Utc;
 use metrics_core::{Builder, Drain, Observe};
 use metrics_runtime::observers::PrometheusBuilder;
 use metrics_runtime::Controller;
 use serde_derive::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Home page
 #[get("/")]
@@ -33,54 +37,341 @@ fn prometheus(state: web::Data<Controller>) -> HttpResponse {
 #[derive(Debug, Serialize)]
 struct Series<'a> {
     topic: &'a str,
-    data: Vec<Sample>,
+    resolution: Resolution,
+    data: Points,
 }
 
+#[derive(Debug, Serialize)]
+struct AggregatedSeries<'a> {
+    topic: &'a str,
+    agg: Aggregation,
+    step: i64,
+    data: Vec<Bucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn error_response(status: StatusCode, error: String) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse { error })
+}
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Upper bound on the number of buckets in a single aggregation query
+const MAX_BUCKETS: i64 = 10_000;
+
 #[derive(Deserialize)]
 struct TimeQuery {
+    /// Window ending now, in seconds
     pub t_minus: Option<i64>,
+    /// Start of the time range as epoch time, takes precedence over `t_minus`
+    pub from: Option<i64>,
+    /// End of the time range as epoch time, defaults to now
+    pub to: Option<i64>,
+    /// Bucket size in seconds
+    pub step: Option<i64>,
+    /// Aggregation applied to each bucket
+    pub agg: Option<Aggregation>,
+}
+
+/// Data selected by a `TimeQuery`
+enum Selection {
+    Range {
+        from: i64,
+        to: i64,
+        resolution: Resolution,
+    },
+    Aggregate {
+        from: i64,
+        to: i64,
+        step: i64,
+        agg: Aggregation,
+    },
+}
+
+impl TimeQuery {
+    fn selection(&self) -> Result<Selection, HttpResponse> {
+        let now = Utc::now().timestamp();
+        let out_of_range = || {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid time range: out of bounds".to_owned(),
+            )
+        };
+        let to = self.to.unwrap_or(i64::MAX);
+        let from = match self.from {
+            Some(from) => from,
+            None => now
+                .checked_sub(self.t_minus.unwrap_or(0))
+                .ok_or_else(out_of_range)?,
+        };
+        if from >= to {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid time range: from {} is not before to {}", from, to),
+            ));
+        }
+        let window = to.min(now).checked_sub(from).ok_or_else(out_of_range)?;
+        let resolution = Resolution::for_window(window, now.saturating_sub(from));
+
+        if self.step.is_none() && self.agg.is_none() {
+            return Ok(Selection::Range {
+                from,
+                to,
+                resolution,
+            });
+        }
+
+        let step = self.step.unwrap_or_else(|| resolution.seconds());
+        if step <= 0 {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid step: {} is not a positive number of seconds", step),
+            ));
+        }
+        if window / step > MAX_BUCKETS {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Too many buckets: at most {} are allowed", MAX_BUCKETS),
+            ));
+        }
+        Ok(Selection::Aggregate {
+            from,
+            to,
+            step,
+            agg: self.agg.unwrap_or(Aggregation::Mean),
+        })
+    }
+}
+
+impl Selection {
+    fn apply(&self, series: &TimeSeries) -> Result<serde_json::Value, HttpResponse> {
+        let topic = series.topic.as_str();
+        let result = match *self {
+            Selection::Range {
+                from,
+                to,
+                resolution,
+            } => serde_json::to_value(Series {
+                topic,
+                resolution,
+                data: series.range(from, to, resolution),
+            }),
+            Selection::Aggregate {
+                from,
+                to,
+                step,
+                agg,
+            } => serde_json::to_value(AggregatedSeries {
+                topic,
+                agg,
+                step,
+                data: series.aggregate(from, to, step, agg),
+            }),
+        };
+        result.map_err(|e| {
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not serialize the time series of {}: {}", topic, e),
+            )
+        })
+    }
+}
+
+fn unknown_topic(topic: &str) -> HttpResponse {
+    error_response(StatusCode::NOT_FOUND, format!("Unknown topic: {}", topic))
+}
+
+#[derive(Deserialize)]
+struct TopicFilter {
+    /// Comma separated list of topics, defaults to all topics when absent or empty
+    pub topics: Option<String>,
 }
 
 #[get("/metrics")]
 fn time_series(
-    state: web::Data<Vec<Arc<TimeSeries>>>,
+    state: web::Data<Arc<Scraper>>,
     query: web::Query<TimeQuery>,
+    filter: web::Query<TopicFilter>,
 ) -> HttpResponse {
-    let min_time = Utc::now().timestamp() - query.t_minus.unwrap_or(0);
-    let data: Vec<_> = state
+    let selection = match query.selection() {
+        Ok(selection) => selection,
+        Err(response) => return response,
+    };
+    let state = state.time_series();
+
+    let topics = filter
+        .topics
         .iter()
-        .filter_map(|series| {
-            series
-                .data
-                .read()
-                .map(|store| store.clone())
-                .ok()
-                .map(|values| Series {
-                    topic: series.topic.as_str(),
-                    data: values
-                        .into_iter()
-                        .filter(|sample| sample.time > min_time)
-                        .collect(),
-                })
-        })
-        .collect();
-    HttpResponse::build(StatusCode::OK).json(data)
+        .flat_map(|topics| topics.split(','))
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .collect::<Vec<_>>();
+    let selected = if topics.is_empty() {
+        state.iter().collect::<Vec<_>>()
+    } else {
+        let mut selected = Vec::<&Arc<TimeSeries>>::new();
+        for topic in topics {
+            match state.iter().find(|series| series.topic == topic) {
+                // Topics listed more than once are only reported once
+                Some(_) if selected.iter().any(|other| other.topic == topic) => {}
+                Some(series) => selected.push(series),
+                None => return unknown_topic(topic),
+            }
+        }
+        selected
+    };
+    let data = selected
+        .into_iter()
+        .map(|series| selection.apply(series))
+        .collect::<Result<Vec<_>, _>>();
+    match data {
+        Ok(data) => HttpResponse::build(StatusCode::OK).json(data),
+        Err(response) => response,
+    }
 }
 
-pub fn run(
-    config: ServerConfig,
-    ts: Vec<Arc<TimeSeries>>,
-    scraper_metrics: Controller,
-) -> std::io::Result<Server> {
-    let ts = web::Data::new(ts);
-    let scraper_metrics = web::Data::new(scraper_metrics);
+#[get("/metrics/{topic}")]
+fn topic_series(
+    state: web::Data<Arc<Scraper>>,
+    topic: web::Path<String>,
+    query: web::Query<TimeQuery>,
+) -> HttpResponse {
+    let selection = match query.selection() {
+        Ok(selection) => selection,
+        Err(response) => return response,
+    };
+    match state
+        .time_series()
+        .iter()
+        .find(|series| series.topic == *topic)
+    {
+        Some(series) => match selection.apply(series) {
+            Ok(data) => HttpResponse::build(StatusCode::OK).json(data),
+            Err(response) => response,
+        },
+        None => unknown_topic(&topic),
+    }
+}
+
+/// Bearer token guarding topic management
+struct AdminToken(Option<Secret>);
+
+impl AdminToken {
+    /// Verify the request carries the admin token, rejecting it otherwise
+    fn authorize(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+        let token = match &self.0 {
+            Some(token) => token,
+            None => {
+                return Err(error_response(
+                    StatusCode::FORBIDDEN,
+                    "Topic management is disabled".to_owned(),
+                ))
+            }
+        };
+        let provided = request
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), token.expose().as_bytes()) => {
+                Ok(())
+            }
+            _ => Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid bearer token".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Compare secrets in a time which only depends on their length, so they can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Status of the stream feeding every subscribed & retained topic
+#[get("/topics")]
+fn topic_status(scraper: web::Data<Arc<Scraper>>) -> HttpResponse {
+    HttpResponse::build(StatusCode::OK).json(scraper.topic_status())
+}
+
+#[derive(Deserialize)]
+struct NewTopic {
+    pub topic: String,
+}
+
+/// Start tracking an additional topic
+#[post("/topics")]
+fn add_topic(
+    request: HttpRequest,
+    scraper: web::Data<Arc<Scraper>>,
+    admin_token: web::Data<AdminToken>,
+    body: web::Json<NewTopic>,
+) -> HttpResponse {
+    if let Err(response) = admin_token.authorize(&request) {
+        return response;
+    }
+    let topic = body.into_inner().topic.trim().to_owned();
+    if let Err(reason) = config::validate_topic(&topic) {
+        return error_response(StatusCode::BAD_REQUEST, reason);
+    }
+    match scraper.subscribe_to(topic) {
+        Ok(()) => HttpResponse::build(StatusCode::CREATED).finish(),
+        Err(err @ TopicError::AlreadySubscribed(_)) | Err(err @ TopicError::TooMany) => {
+            error_response(StatusCode::CONFLICT, err.to_string())
+        }
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct RemoveTopic {
+    /// Keep serving the collected time series, defaults to `false`
+    pub retain: Option<bool>,
+}
+
+/// Stop tracking a topic
+#[delete("/topics/{topic}")]
+fn remove_topic(
+    request: HttpRequest,
+    scraper: web::Data<Arc<Scraper>>,
+    admin_token: web::Data<AdminToken>,
+    topic: web::Path<String>,
+    query: web::Query<RemoveTopic>,
+) -> HttpResponse {
+    if let Err(response) = admin_token.authorize(&request) {
+        return response;
+    }
+    match scraper.unsubscribe(&topic, query.retain.unwrap_or(false)) {
+        Ok(()) => HttpResponse::build(StatusCode::NO_CONTENT).finish(),
+        Err(err @ TopicError::NotSubscribed(_)) => {
+            error_response(StatusCode::NOT_FOUND, err.to_string())
+        }
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+pub fn run(config: ServerConfig, scraper: Arc<Scraper>) -> std::io::Result<Server> {
+    let scraper_metrics = web::Data::new(scraper.metrics());
+    let scraper = web::Data::new(scraper);
+    let admin_token = web::Data::new(AdminToken(config.admin_token.clone()));
     let create_server = move || {
         App::new()
-            .register_data(ts.clone())
+            .register_data(scraper.clone())
             .register_data(scraper_metrics.clone())
+            .register_data(admin_token.clone())
             .wrap(middleware::Logger::default())
             .service(index)
             .service(time_series)
+            .service(topic_series)
+            .service(topic_status)
+            .service(add_topic)
+            .service(remove_topic)
             .service(prometheus)
             .default_service(
                 web::resource("")
@@ -96,6 +387,62 @@ pub fn run(
     };
 
     HttpServer::new(create_server)
+        // Stop the actor system once the server has shut down, e.g. on SIGTERM
+        .system_exit()
+        .shutdown_timeout(config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
         .bind(format!("{}:{}", config.host, config.port))
         .map(|server| server.start())
 }
+
+/// Deadline of a graceful shutdown, shared by the HTTP server & the scraper
+#[derive(Clone)]
+pub struct ShutdownDeadline {
+    timeout: Duration,
+    /// When the first shutdown signal was received
+    signalled: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ShutdownDeadline {
+    /// Time left of the `shutdown_timeout`, counting from the shutdown signal
+    pub fn remaining(&self) -> Duration {
+        match *self.signalled.lock().expect("Shutdown lock was poisoned") {
+            Some(signalled) => self
+                .timeout
+                .checked_sub(signalled.elapsed())
+                .unwrap_or_default(),
+            None => self.timeout,
+        }
+    }
+}
+
+/// Record when a shutdown signal is received, alongside the server handling it.
+/// Must be called from within the actix system
+pub fn watch_shutdown(timeout: Duration) -> ShutdownDeadline {
+    let deadline = ShutdownDeadline {
+        timeout,
+        signalled: Arc::new(Mutex::new(None)),
+    };
+    #[cfg(unix)]
+    {
+        use futures::{Future, Stream};
+        use log::error;
+        use tokio_signal::unix::{Signal, SIGINT, SIGQUIT, SIGTERM};
+
+        for &signal in &[SIGINT, SIGTERM, SIGQUIT] {
+            let signalled = deadline.signalled.clone();
+            actix_rt::spawn(
+                Signal::new(signal)
+                    .flatten_stream()
+                    .map_err(|err| error!("Could not listen for shutdown signals: {}", err))
+                    .for_each(move |_| {
+                        signalled
+                            .lock()
+                            .expect("Shutdown lock was poisoned")
+                            .get_or_insert_with(Instant::now);
+                        Ok(())
+                    }),
+            );
+        }
+    }
+    deadline
+}
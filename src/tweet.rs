@@ -1,16 +1,108 @@
+use chrono::DateTime;
 use serde_derive::{Deserialize, Serialize};
 
+const TWITTER_DATE_FORMAT: &str = "%a %b %d %H:%M:%S %z %Y";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Limit {
     pub track: u64,
     pub timestamp_ms: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct User {
+    pub id: Option<u64>,
+    pub id_str: Option<String>,
+    #[serde(default)]
+    pub screen_name: String,
+    pub name: Option<String>,
+    pub followers_count: Option<u64>,
+    #[serde(default)]
+    pub verified: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hashtag {
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserMention {
+    pub id_str: String,
+    pub screen_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Url {
+    pub url: String,
+    pub expanded_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Entities {
+    #[serde(default)]
+    pub hashtags: Vec<Hashtag>,
+    #[serde(default)]
+    pub user_mentions: Vec<UserMention>,
+    #[serde(default)]
+    pub urls: Vec<Url>,
+}
+
+/// Complete body of tweets exceeding 140 characters
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExtendedTweet {
+    pub full_text: String,
+    pub entities: Option<Entities>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Content {
     pub created_at: String,
-    //    pub id_str: String,
+    pub id: Option<u64>,
+    pub id_str: Option<String>,
     pub text: String,
+    pub user: Option<User>,
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub entities: Entities,
+    /// Set when `text` was cut off, the complete body is available in `extended_tweet`
+    #[serde(default)]
+    pub truncated: bool,
+    pub extended_tweet: Option<ExtendedTweet>,
+    /// Original status of a retweet
+    pub retweeted_status: Option<Box<Content>>,
+    /// Status quoted by this tweet
+    pub quoted_status: Option<Box<Content>>,
+    pub retweet_count: Option<u64>,
+    pub timestamp_ms: Option<String>,
+}
+
+impl Content {
+    /// Complete, untruncated body of the tweet.
+    /// Retweets resolve to the body of their original status
+    pub fn full_text(&self) -> &str {
+        match (&self.retweeted_status, &self.extended_tweet) {
+            (Some(original), _) => original.full_text(),
+            (None, Some(extended)) => extended.full_text.as_str(),
+            (None, None) => self.text.as_str(),
+        }
+    }
+
+    /// Epoch time at which the tweet was created
+    pub fn time(&self) -> Option<i64> {
+        self.timestamp_ms
+            .as_ref()
+            .and_then(|ms| ms.parse::<i64>().ok())
+            .map(|ms| ms / 1000)
+            .or_else(|| parse_time(&self.created_at))
+    }
+}
+
+/// Parse a Twitter date string as epoch time
+fn parse_time(time_str: &str) -> Option<i64> {
+    DateTime::parse_from_str(time_str, TWITTER_DATE_FORMAT)
+        .ok()
+        .map(|time| time.timestamp())
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,28 +110,116 @@ pub struct ApiLimit {
     pub limit: Limit,
 }
 
+/// Status which was deleted by its author
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Disconnect {
+pub struct DeletedStatus {
+    pub id: u64,
+    pub id_str: String,
+    pub user_id: u64,
+    pub user_id_str: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeletionNotice {
+    pub status: DeletedStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Delete {
+    pub delete: DeletionNotice,
+}
+
+/// Request to strip the location information of a user's statuses
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScrubGeoNotice {
+    pub user_id: u64,
+    pub up_to_status_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScrubGeo {
+    pub scrub_geo: ScrubGeoNotice,
+}
+
+/// Status which is no longer available in some countries
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusWithheldNotice {
+    pub id: u64,
+    pub user_id: u64,
+    pub withheld_in_countries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusWithheld {
+    pub status_withheld: StatusWithheldNotice,
+}
+
+/// User whose statuses are no longer available in some countries
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserWithheldNotice {
+    pub id: u64,
+    pub withheld_in_countries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserWithheld {
+    pub user_withheld: UserWithheldNotice,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisconnectNotice {
     pub code: u16,
     pub stream_name: String,
     pub reason: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct StallWarning {
-    pub code: u16,
-    pub stream_name: String,
+pub struct Disconnect {
+    pub disconnect: DisconnectNotice,
+}
+
+/// Either a stall warning (`FALLING_BEHIND`), which reports how full the client's queue is,
+/// or a `FOLLOWS_OVER_LIMIT` warning
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WarningNotice {
+    pub code: String,
     pub message: String,
-    pub percent_full: u8,
+    pub percent_full: Option<u8>,
+    pub user_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Warning {
+    pub warning: WarningNotice,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum Tweet {
     ApiLimit(ApiLimit),
-    Content(Content),
+    Content(Box<Content>),
+    Delete(Delete),
+    ScrubGeo(ScrubGeo),
+    StatusWithheld(StatusWithheld),
+    UserWithheld(UserWithheld),
     Disconnect(Disconnect),
-    StallWarning(StallWarning),
+    Warning(Warning),
+}
+
+impl Tweet {
+    /// Kind of message, as used in metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Tweet::ApiLimit(_) => "limit",
+            Tweet::Content(_) => "status",
+            Tweet::Delete(_) => "delete",
+            Tweet::ScrubGeo(_) => "scrub_geo",
+            Tweet::StatusWithheld(_) => "status_withheld",
+            Tweet::UserWithheld(_) => "user_withheld",
+            Tweet::Disconnect(_) => "disconnect",
+            Tweet::Warning(_) => "warning",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -49,6 +229,178 @@ mod test {
     #[test]
     fn parse_limit_msg() {
         let msg = "{\"limit\":{\"track\":1678,\"timestamp_ms\":\"1571317682725\"}}";
-        serde_json::from_str::<Tweet>(&msg).expect("Could not decode limit msg");
+        serde_json::from_str::<Tweet>(msg).expect("Could not decode limit msg");
+    }
+
+    #[test]
+    fn parse_control_msgs() {
+        let msgs = [
+            (
+                r#"{"delete":{"status":{"id":1234,"id_str":"1234","user_id":3,"user_id_str":"3"}}}"#,
+                "delete",
+            ),
+            (
+                r#"{"scrub_geo":{"user_id":14090452,"user_id_str":"14090452","up_to_status_id":23260136625,"up_to_status_id_str":"23260136625"}}"#,
+                "scrub_geo",
+            ),
+            (
+                r#"{"status_withheld":{"id":1234567890,"user_id":123456,"withheld_in_countries":["DE","AR"]}}"#,
+                "status_withheld",
+            ),
+            (
+                r#"{"user_withheld":{"id":123456,"withheld_in_countries":["DE","AR"]}}"#,
+                "user_withheld",
+            ),
+            (
+                r#"{"disconnect":{"code":4,"stream_name":"sibyl-statuses","reason":"Stall detected"}}"#,
+                "disconnect",
+            ),
+            (
+                r#"{"warning":{"code":"FALLING_BEHIND","message":"Your connection is falling behind","percent_full":60}}"#,
+                "warning",
+            ),
+            (
+                r#"{"warning":{"code":"FOLLOWS_OVER_LIMIT","message":"No more followers","user_id":13}}"#,
+                "warning",
+            ),
+        ];
+        for (msg, kind) in msgs.iter() {
+            let tweet = serde_json::from_str::<Tweet>(msg)
+                .unwrap_or_else(|err| panic!("Could not decode {}: {}", msg, err));
+            assert_eq!(tweet.kind(), *kind);
+        }
+
+        match serde_json::from_str::<Tweet>(msgs[0].0) {
+            Ok(Tweet::Delete(delete)) => assert_eq!(delete.delete.status.id, 1234),
+            other => panic!("Expected deletion notice, got {:?}", other),
+        }
+        match serde_json::from_str::<Tweet>(msgs[5].0) {
+            Ok(Tweet::Warning(warning)) => assert_eq!(warning.warning.percent_full, Some(60)),
+            other => panic!("Expected stall warning, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_twitter_date_string() {
+        let sample_string = "Wed Oct 16 20:18:02 +0000 2019";
+        assert_eq!(
+            parse_time(sample_string),
+            Some(1571257082),
+            "Could not parse Twitter date string: {}",
+            sample_string
+        );
+    }
+
+    #[test]
+    fn tweet_creation_time() {
+        let msg = "{\"created_at\":\"Wed Oct 16 20:18:02 +0000 2019\",\"text\":\"\"}";
+        let mut content = match serde_json::from_str::<Tweet>(msg) {
+            Ok(Tweet::Content(content)) => *content,
+            other => panic!("Expected tweet content, got {:?}", other),
+        };
+        assert_eq!(content.time(), Some(1571257082));
+        content.timestamp_ms = Some("1571257083725".to_owned());
+        assert_eq!(content.time(), Some(1571257083));
+    }
+
+    fn parse_fixture(fixture: &str) -> Content {
+        match serde_json::from_str::<Tweet>(fixture) {
+            Ok(Tweet::Content(content)) => *content,
+            other => panic!("Expected tweet content, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_tweet() {
+        let content = parse_fixture(include_str!("../fixtures/tweet.json"));
+        assert_eq!(content.id, Some(1184822371436912640));
+        assert_eq!(content.id_str.as_deref(), Some("1184822371436912640"));
+        assert_eq!(content.lang.as_deref(), Some("en"));
+        let user = content.user.expect("Tweet without author");
+        assert_eq!(user.id, Some(2836491722));
+        assert_eq!(user.id_str.as_deref(), Some("2836491722"));
+        assert_eq!(user.screen_name, "example_cook");
+        assert_eq!(user.followers_count, Some(412));
+
+        let hashtags = content
+            .entities
+            .hashtags
+            .iter()
+            .map(|hashtag| hashtag.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(hashtags, vec!["food", "sundayfunday"]);
+        assert_eq!(
+            content.entities.user_mentions[0].screen_name,
+            "example_chef"
+        );
+        assert_eq!(
+            content.entities.urls[0].expanded_url.as_deref(),
+            Some("https://example.com/p/roast")
+        );
+        assert!(!content.truncated);
+        assert!(content.retweeted_status.is_none());
+        assert!(content.quoted_status.is_none());
+
+        let partial = parse_fixture(
+            r#"{"created_at":"Thu Oct 17 13:08:02 +0000 2019","text":"","user":{"name":"Anonymous"}}"#,
+        );
+        let author = partial.user.expect("Author should be parsed");
+        assert_eq!(author.id, None, "Authors without an id are tolerated");
+        assert_eq!(author.id_str, None);
+    }
+
+    #[test]
+    fn parse_extended_retweet() {
+        let content = parse_fixture(include_str!("../fixtures/retweet_extended.json"));
+        assert!(content.text.starts_with("RT @musicdaily"));
+        assert_eq!(content.entities.user_mentions[0].screen_name, "musicdaily");
+
+        let original = content
+            .retweeted_status
+            .expect("Retweet without original status");
+        assert_eq!(original.id, Some(1184815447312580608));
+        assert!(original.truncated);
+        assert!(original.timestamp_ms.is_none());
+        let extended = original
+            .extended_tweet
+            .expect("Truncated tweet without extended tweet");
+        assert!(extended.full_text.ends_with("#music #AOTY"));
+        assert_eq!(
+            extended.entities.map(|entities| entities.hashtags.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn resolve_full_text() {
+        let tweet = parse_fixture(include_str!("../fixtures/tweet.json"));
+        assert_eq!(tweet.full_text(), tweet.text);
+
+        let retweet = parse_fixture(include_str!("../fixtures/retweet_extended.json"));
+        assert!(retweet
+            .full_text()
+            .starts_with("The new album is a masterpiece"));
+        assert!(retweet
+            .full_text()
+            .ends_with("Album of the year, no contest #music #AOTY"));
+
+        let quote = parse_fixture(include_str!("../fixtures/quote.json"));
+        assert_eq!(
+            quote.full_text(),
+            "Hard disagree, this trip was a disaster from day one https://t.co/q9W2cLk4Vd"
+        );
+    }
+
+    #[test]
+    fn parse_quote() {
+        let content = parse_fixture(include_str!("../fixtures/quote.json"));
+        assert!(content.retweeted_status.is_none());
+        let quoted = content.quoted_status.expect("Quote without quoted status");
+        assert_eq!(quoted.id_str.as_deref(), Some("1184790231558795265"));
+        assert_eq!(
+            quoted.user.map(|user| user.screen_name),
+            Some("wanderlust_co".to_owned())
+        );
+        assert_eq!(quoted.entities.hashtags[0].text, "travel");
     }
 }